@@ -1,10 +1,14 @@
 use anyhow::Result;
-use clap::{arg, command, Args, ValueHint};
-use std::{fs, io, path::PathBuf};
+use clap::{Args, ValueHint};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use tempfile::NamedTempFile;
 use thiserror::Error;
 
-use crate::env;
+use crate::{crypto::EncryptionConfig, env};
 
 #[derive(Error, Debug)]
 pub enum CacheError {
@@ -14,6 +18,10 @@ pub enum CacheError {
     Io(#[from] io::Error),
     #[error("cannot store the resulting env file - there was a problem during serialization")]
     Serialization(#[from] serde_json::Error),
+    #[error("cannot store the resulting env file - there was a problem during encryption")]
+    Encryption(#[source] anyhow::Error),
+    #[error("refusing to store the env file in '{0}' - the directory is writable by group or others (use --force to override)")]
+    InsecureDirectory(PathBuf),
 }
 
 /// Caches the environment variables from KeyVault into local file.
@@ -25,10 +33,18 @@ pub struct Cache {
     #[command(flatten)]
     output_file: OutputFileConfig,
 
+    #[command(flatten)]
+    encryption: EncryptionConfig,
+
     /// If set, `kvenv` will use OS's environment at the point in time when the environment is
     /// downloaded.
     #[arg(short = 'e', long)]
     snapshot_env: bool,
+
+    /// If set, the cached environment expires after the specified time (e.g. `15m` or `1h 30m`)
+    /// and `run-with` refuses to use it afterwards.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    ttl: Option<Duration>,
 }
 
 #[derive(Args, Debug)]
@@ -42,39 +58,110 @@ pub struct OutputFileConfig {
     /// will be created there.
     #[arg(short = 'd', long, value_parser, value_hint = ValueHint::DirPath, group = "output")]
     output_dir: Option<PathBuf>,
+
+    /// The permissions of the output file, in octal.
+    #[arg(long, value_parser = parse_file_mode, default_value = "600")]
+    file_mode: u32,
+
+    /// Store the output file even if the directory is writable by group or others.
+    #[arg(long)]
+    force: bool,
+}
+
+fn parse_file_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("'{s}' is not a valid octal file mode (e.g. 600)")),
+    }
 }
 
+/// The output file is always written to a temporary file first. `Direct` one is then atomically
+/// renamed to the requested path, so readers never see a partially written file.
 enum OutputFile {
-    Direct(fs::File, PathBuf),
+    Direct(NamedTempFile, PathBuf),
     Temp(NamedTempFile),
 }
 
-fn get_output_file(cfg: OutputFileConfig) -> Result<OutputFile> {
-    if let Some(f) = cfg.output_file {
-        let file = fs::File::create(&f).map_err(CacheError::Io)?;
-        Ok(OutputFile::Direct(file, f))
-    } else {
-        let mut b = tempfile::Builder::new();
-        b.prefix("kvenv-").suffix(".json").rand_bytes(5);
-        let file = if let Some(d) = cfg.output_dir {
-            b.tempfile_in(d)
-        } else {
-            b.tempfile()
-        };
-        let file = file.map_err(CacheError::Io)?;
-        Ok(OutputFile::Temp(file))
+/// Checks that no one else can replace or remove the file in the directory.
+#[cfg(unix)]
+fn check_directory(dir: &Path, force: bool) -> Result<(), CacheError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(dir)?.permissions().mode();
+    if !force && mode & 0o022 != 0 {
+        return Err(CacheError::InsecureDirectory(dir.to_owned()));
     }
+    Ok(())
 }
 
-fn store_env(e: env::ProcessEnv, out_file: OutputFile) -> Result<PathBuf> {
+#[cfg(not(unix))]
+fn check_directory(_dir: &Path, _force: bool) -> Result<(), CacheError> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_file_mode(f: &NamedTempFile, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    f.as_file()
+        .set_permissions(fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_file_mode(_f: &NamedTempFile, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+fn get_output_file(cfg: OutputFileConfig, encrypted: bool) -> Result<OutputFile> {
+    let mut b = tempfile::Builder::new();
+    let file = if let Some(f) = cfg.output_file {
+        let dir = match f.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d.to_owned(),
+            _ => PathBuf::from("."),
+        };
+        check_directory(&dir, cfg.force)?;
+        let file = b.prefix(".kvenv-").tempfile_in(dir)?;
+        set_file_mode(&file, cfg.file_mode)?;
+        OutputFile::Direct(file, f)
+    } else {
+        let suffix = if encrypted { ".json.age" } else { ".json" };
+        b.prefix("kvenv-").suffix(suffix).rand_bytes(5);
+        let dir = cfg.output_dir.unwrap_or_else(std::env::temp_dir);
+        check_directory(&dir, cfg.force)?;
+        let file = b.tempfile_in(dir)?;
+        set_file_mode(&file, cfg.file_mode)?;
+        OutputFile::Temp(file)
+    };
+    Ok(file)
+}
+
+fn write_env(e: &env::ProcessEnv, w: impl io::Write, encryption: &EncryptionConfig) -> Result<()> {
+    encryption
+        .write(w, |w| {
+            Ok(e.to_writer(w).map_err(CacheError::Serialization)?)
+        })
+        .map_err(|e| match e.downcast::<CacheError>() {
+            Ok(e) => e,
+            Err(e) => CacheError::Encryption(e),
+        })?;
+    Ok(())
+}
+
+fn store_env(
+    e: env::ProcessEnv,
+    out_file: OutputFile,
+    encryption: &EncryptionConfig,
+) -> Result<PathBuf> {
     match out_file {
-        OutputFile::Direct(f, p) => {
-            e.to_writer(f).map_err(CacheError::Serialization)?;
+        OutputFile::Direct(mut t, p) => {
+            write_env(&e, t.as_file_mut(), encryption)?;
+            t.as_file().sync_all().map_err(CacheError::Io)?;
+            t.persist(&p).map_err(|e| CacheError::Io(e.error))?;
             Ok(p)
         }
         OutputFile::Temp(mut t) => {
-            e.to_writer(t.as_file_mut())
-                .map_err(CacheError::Serialization)?;
+            write_env(&e, t.as_file_mut(), encryption)?;
+            t.as_file().sync_all().map_err(CacheError::Io)?;
             let (_, p) = t.keep().map_err(|e| CacheError::Io(e.error))?;
             Ok(p.as_path().to_owned())
         }
@@ -82,65 +169,152 @@ fn store_env(e: env::ProcessEnv, out_file: OutputFile) -> Result<PathBuf> {
 }
 
 pub fn run_cache(c: Cache) -> Result<()> {
-    let cached_env = env::download_env(c.env, c.snapshot_env).map_err(CacheError::Load)?;
-    let out_file = get_output_file(c.output_file)?;
-    let path = store_env(cached_env, out_file)?;
+    let mut cached_env = env::download_env(c.env, c.snapshot_env).map_err(CacheError::Load)?;
+    if let Some(ttl) = c.ttl {
+        cached_env = cached_env.with_ttl(ttl);
+    }
+    let out_file = get_output_file(c.output_file, c.encryption.is_enabled())?;
+    let path = store_env(cached_env, out_file, &c.encryption)?;
     println!("{}", path.display());
     Ok(())
 }
 
-#[cfg(feature = "integration-tests")]
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[cfg(feature = "integration-tests")]
     use std::io::prelude::*;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    #[cfg(unix)]
+    fn config(dir: &Path, force: bool) -> OutputFileConfig {
+        OutputFileConfig {
+            output_file: Some(dir.join("env.json")),
+            output_dir: None,
+            file_mode: 0o600,
+            force,
+        }
+    }
+
+    #[cfg(unix)]
+    fn set_dir_mode(dir: &Path, mode: u32) {
+        fs::set_permissions(dir, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn parses_file_mode() {
+        assert_eq!(Ok(0o640), parse_file_mode("640"));
+        assert_eq!(Ok(0o600), parse_file_mode("0600"));
+        assert!(parse_file_mode("800").is_err());
+        assert!(parse_file_mode("1777").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stores_file_atomically_with_restrictive_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        set_dir_mode(dir.path(), 0o755);
+        let path = dir.path().join("env.json");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
 
+        let out = get_output_file(config(dir.path(), false), false).unwrap();
+        let env = env::ProcessEnv::new(vec![], vec![], false);
+        store_env(env, out, &EncryptionConfig::default()).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        assert_ne!("old", fs::read_to_string(&path).unwrap());
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_world_writable_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        set_dir_mode(dir.path(), 0o777);
+
+        assert!(matches!(
+            get_output_file(config(dir.path(), false), false)
+                .err()
+                .unwrap()
+                .downcast::<CacheError>(),
+            Ok(CacheError::InsecureDirectory(_))
+        ));
+        assert!(get_output_file(config(dir.path(), true), false).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stores_in_sticky_directory_only_with_force() {
+        let dir = tempfile::tempdir().unwrap();
+        for mode in [0o1777, 0o1770] {
+            set_dir_mode(dir.path(), mode);
+            assert!(get_output_file(config(dir.path(), false), false).is_err());
+            assert!(get_output_file(config(dir.path(), true), false).is_ok());
+        }
+    }
+
+    #[cfg(feature = "integration-tests")]
     #[test]
     fn output_file_direct() {
         let cfg = OutputFileConfig {
             output_file: Some("./test-file.json".into()),
             output_dir: None,
+            file_mode: 0o600,
+            force: false,
         };
         assert_direct(cfg);
 
         let cfg = OutputFileConfig {
             output_file: Some("./test-file.json".into()),
             output_dir: Some("./should-be-ignored".into()),
+            file_mode: 0o600,
+            force: false,
         };
         assert_direct(cfg);
     }
 
+    #[cfg(feature = "integration-tests")]
     #[test]
     fn output_file_temp() {
+        // The system temporary directory is usually world-writable.
         let cfg = OutputFileConfig {
             output_file: None,
             output_dir: None,
+            file_mode: 0o600,
+            force: true,
         };
         assert_temp(cfg);
 
         let cfg = OutputFileConfig {
             output_file: None,
             output_dir: Some(".".into()),
+            file_mode: 0o600,
+            force: false,
         };
         assert_temp(cfg);
     }
 
+    #[cfg(feature = "integration-tests")]
     fn assert_direct(cfg: OutputFileConfig) {
         let file_name = cfg.output_file.clone().unwrap();
-        let f = get_output_file(cfg).unwrap();
+        let f = get_output_file(cfg, false).unwrap();
         match f {
-            OutputFile::Direct(mut f, _) => {
-                write!(f, "test").unwrap(); // Try write
-                drop(f);
+            OutputFile::Direct(mut f, p) => {
+                write!(f.as_file_mut(), "test").unwrap(); // Try write
+                f.persist(p).unwrap();
                 fs::remove_file(file_name).unwrap();
             }
             _ => panic!("should return `Direct` case"),
         };
     }
 
+    #[cfg(feature = "integration-tests")]
     fn assert_temp(cfg: OutputFileConfig) {
-        let f = get_output_file(cfg).unwrap();
+        let f = get_output_file(cfg, false).unwrap();
         match f {
             OutputFile::Temp(mut f) => {
                 write!(f.as_file_mut(), "test").unwrap(); // Try write
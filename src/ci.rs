@@ -0,0 +1,215 @@
+use clap::Args;
+use std::{
+    collections::hash_map::RandomState,
+    fs,
+    hash::{BuildHasher, Hasher},
+    io::{self, Write},
+    path::PathBuf,
+};
+use thiserror::Error;
+
+use crate::env::ProcessEnv;
+
+#[derive(Error, Debug)]
+pub enum CiError {
+    #[error("'{0}' is not set - is kvenv running in GitHub Actions?")]
+    MissingFile(&'static str),
+    #[error("variable '{0}' was not downloaded from the secret storage")]
+    UnknownKey(String),
+    #[error("cannot write to '{0}'")]
+    Io(PathBuf, #[source] io::Error),
+    #[error("cannot register the values with the log masking")]
+    Mask(#[source] io::Error),
+}
+
+/// Integration with the CI/CD log masking.
+#[derive(Args, Debug, Default)]
+pub struct CiConfig {
+    /// Register the downloaded values with the CI's log masking before the command starts. GitHub
+    /// Actions (`GITHUB_ACTIONS`) and GitLab CI (`GITLAB_CI`) are detected automatically. GitLab
+    /// cannot mask values at runtime, so the command's output is redacted by kvenv there.
+    #[arg(long)]
+    ci_mask: bool,
+
+    /// Write the downloaded variable to `$GITHUB_ENV`, so it is available to the subsequent steps
+    /// of the GitHub Actions job. The value is masked. Can be specified multiple times.
+    #[arg(long, value_name = "KEY")]
+    github_env: Vec<String>,
+
+    /// Write the downloaded variable to `$GITHUB_OUTPUT` as an output of the GitHub Actions step.
+    /// The value is masked. Can be specified multiple times.
+    #[arg(long, value_name = "KEY")]
+    github_output: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CiProvider {
+    GitHub,
+    GitLab,
+}
+
+impl CiProvider {
+    fn detect<F>(var: F) -> Option<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let is_set = |name| var(name).is_some_and(|v| v == "true");
+        if is_set("GITHUB_ACTIONS") {
+            Some(CiProvider::GitHub)
+        } else if is_set("GITLAB_CI") {
+            Some(CiProvider::GitLab)
+        } else {
+            None
+        }
+    }
+
+    fn current() -> Option<Self> {
+        Self::detect(|name| std::env::var(name).ok())
+    }
+}
+
+/// Escapes the data of a GitHub Actions workflow command.
+fn escape_command_data(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Writes the `::add-mask::` workflow commands for the values. Every line of multiline values is
+/// masked separately, as GitHub matches the masks line by line.
+fn write_masks<'a, W, I>(mut w: W, values: I) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a str>,
+{
+    for value in values {
+        for line in value.lines().filter(|l| !l.trim().is_empty()) {
+            writeln!(w, "::add-mask::{}", escape_command_data(line))?;
+        }
+    }
+    w.flush()
+}
+
+/// A random delimiter for the `KEY<<DELIMITER` syntax that does not occur in the value.
+fn delimiter(value: &str) -> String {
+    loop {
+        let random = RandomState::new().build_hasher().finish();
+        let delimiter = format!("kvenv_{random:016x}");
+        if !value.contains(&delimiter) {
+            return delimiter;
+        }
+    }
+}
+
+/// Writes the variables in the multiline-safe format of `$GITHUB_ENV` and `$GITHUB_OUTPUT` files.
+fn write_github_file<W: Write>(mut w: W, vars: &[(&str, &str)]) -> io::Result<()> {
+    for (key, value) in vars {
+        let delimiter = delimiter(value);
+        writeln!(w, "{key}<<{delimiter}\n{value}\n{delimiter}")?;
+    }
+    w.flush()
+}
+
+impl CiConfig {
+    /// Whether kvenv needs to redact the command's output itself, as the CI cannot mask values.
+    pub fn redact_output(&self) -> bool {
+        self.ci_mask && CiProvider::current() == Some(CiProvider::GitLab)
+    }
+
+    fn masks_on_github(&self) -> bool {
+        self.ci_mask && CiProvider::current() == Some(CiProvider::GitHub)
+    }
+
+    /// Registers the downloaded values with the CI's log masking.
+    pub fn mask(&self, env: &ProcessEnv) -> Result<(), CiError> {
+        if self.masks_on_github() {
+            write_masks(io::stdout().lock(), env.secret_values()).map_err(CiError::Mask)?;
+        }
+        Ok(())
+    }
+
+    fn export(&self, env: &ProcessEnv, keys: &[String], var: &'static str) -> Result<(), CiError> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let path = std::env::var_os(var)
+            .map(PathBuf::from)
+            .ok_or(CiError::MissingFile(var))?;
+
+        let vars = keys
+            .iter()
+            .map(|k| match env.get_downloaded(k) {
+                Some(v) => Ok((k.as_str(), v)),
+                None => Err(CiError::UnknownKey(k.clone())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if !self.masks_on_github() {
+            let values = vars.iter().map(|(_, v)| *v);
+            write_masks(io::stdout().lock(), values).map_err(CiError::Mask)?;
+        }
+
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|e| CiError::Io(path.clone(), e))?;
+        write_github_file(file, &vars).map_err(|e| CiError::Io(path, e))
+    }
+
+    /// Masks the values and writes the selected variables to `$GITHUB_ENV` and `$GITHUB_OUTPUT`.
+    pub fn apply(&self, env: &ProcessEnv) -> Result<(), CiError> {
+        self.mask(env)?;
+        self.export(env, &self.github_env, "GITHUB_ENV")?;
+        self.export(env, &self.github_output, "GITHUB_OUTPUT")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_provider() {
+        let detect = |vars: &[(&str, &str)]| {
+            CiProvider::detect(|name| {
+                vars.iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.to_string())
+            })
+        };
+
+        assert_eq!(
+            Some(CiProvider::GitHub),
+            detect(&[("GITHUB_ACTIONS", "true")])
+        );
+        assert_eq!(Some(CiProvider::GitLab), detect(&[("GITLAB_CI", "true")]));
+        assert_eq!(None, detect(&[("GITLAB_CI", "false")]));
+        assert_eq!(None, detect(&[]));
+    }
+
+    #[test]
+    fn writes_masks() {
+        let mut out = Vec::new();
+        write_masks(&mut out, ["secret", "100%", "first\n\nsecond\r\n"]).unwrap();
+
+        assert_eq!(
+            "::add-mask::secret\n::add-mask::100%25\n::add-mask::first\n::add-mask::second\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn writes_multiline_values_with_delimiters() {
+        let mut out = Vec::new();
+        write_github_file(&mut out, &[("A", "1"), ("B", "line\nEOF\nline")]).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+
+        assert_eq!(8, lines.len());
+        let (key, delimiter) = lines[3].split_once("<<").unwrap();
+        assert_eq!("B", key);
+        assert!(delimiter.starts_with("kvenv_"));
+        assert_eq!(&["line", "EOF", "line", delimiter], &lines[4..]);
+    }
+}
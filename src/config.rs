@@ -0,0 +1,398 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use clap::{parser::ValueSource, ArgGroup, ArgMatches, Command, CommandFactory};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::Cli;
+
+/// The name of the project-level configuration file.
+pub const CONFIG_FILE_NAME: &str = "kvenv.toml";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("cannot read configuration file '{0}'")]
+    Io(PathBuf, #[source] std::io::Error),
+    #[error("configuration file '{0}' is invalid")]
+    Parse(PathBuf, #[source] toml::de::Error),
+    #[error("profile '{0}' is not defined (searched in: {1})")]
+    ProfileNotFound(String, String),
+    #[error("profile '{0}' has unknown option '{1}'")]
+    UnknownOption(String, String),
+    #[error("profile '{0}' has invalid value for option '{1}' - only strings, numbers, booleans and arrays of these are supported")]
+    InvalidValue(String, String),
+    #[error("cannot find subcommand '{0}' in the arguments to apply the profile to")]
+    SubcommandNotFound(String),
+}
+
+/// A single named profile. Keys are long names of the command-line options (e.g. `aws-region`
+/// or `secret-name`), values are their values.
+pub type Profile = BTreeMap<String, toml::Value>;
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
+}
+
+/// A profile selected with `--profile`, with all the files that contributed to it.
+#[derive(Debug)]
+pub struct SelectedProfile {
+    pub name: String,
+    pub files: Vec<PathBuf>,
+    pub values: Profile,
+}
+
+impl ConfigFile {
+    fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+    }
+}
+
+/// Finds the project-level configuration file, walking up from `dir`.
+fn find_project_file(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|d| d.join(CONFIG_FILE_NAME))
+        .find(|p| p.is_file())
+}
+
+/// The user-level configuration file, i.e. `$XDG_CONFIG_HOME/kvenv/kvenv.toml` or
+/// `~/.config/kvenv/kvenv.toml`.
+fn user_file() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".config")))?;
+    Some(config_dir.join("kvenv").join(CONFIG_FILE_NAME))
+}
+
+/// Loads the profile from the user-level file and the project file (or the explicitly specified
+/// one). Values from the project file take precedence over the user-level ones.
+pub fn load_profile(name: &str, explicit_file: Option<&Path>) -> Result<SelectedProfile> {
+    let project_file = match explicit_file {
+        Some(f) => Some(f.to_owned()),
+        None => find_project_file(&std::env::current_dir()?),
+    };
+    let candidates: Vec<_> = user_file()
+        .filter(|f| f.is_file())
+        .into_iter()
+        .chain(project_file)
+        .collect();
+
+    let mut profile = SelectedProfile {
+        name: name.to_string(),
+        files: Vec::new(),
+        values: Profile::new(),
+    };
+    for path in &candidates {
+        let mut file = ConfigFile::load(path)?;
+        if let Some(values) = file.profiles.remove(name) {
+            profile.values.extend(values);
+            profile.files.push(path.clone());
+        }
+    }
+
+    if profile.files.is_empty() {
+        let searched = candidates
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>();
+        let searched = if searched.is_empty() {
+            format!("no {CONFIG_FILE_NAME} found")
+        } else {
+            searched.join(", ")
+        };
+        bail!(ConfigError::ProfileNotFound(name.to_string(), searched));
+    }
+    Ok(profile)
+}
+
+fn value_to_strings(profile: &str, key: &str, value: &toml::Value) -> Result<Vec<String>> {
+    let invalid = || ConfigError::InvalidValue(profile.to_string(), key.to_string());
+    match value {
+        toml::Value::String(s) => Ok(vec![s.clone()]),
+        toml::Value::Integer(i) => Ok(vec![i.to_string()]),
+        toml::Value::Float(f) => Ok(vec![f.to_string()]),
+        toml::Value::Boolean(b) => Ok(vec![b.to_string()]),
+        toml::Value::Array(a) => a
+            .iter()
+            .map(|v| match v {
+                toml::Value::Array(_) | toml::Value::Table(_) => Err(invalid().into()),
+                v => Ok(value_to_strings(profile, key, v)?.remove(0)),
+            })
+            .collect(),
+        _ => Err(invalid().into()),
+    }
+}
+
+/// Whether the group is a choice of a single option (e.g. `secret`). Groups that allow multiple
+/// options (e.g. `cloud`, or the groups clap derives for every struct of options) are layered
+/// instead, so the profile values are kept.
+fn is_choice(group: &ArgGroup) -> bool {
+    !group.clone().is_multiple()
+}
+
+/// Converts the profile into command-line arguments for the `subcommand`.
+///
+/// Options that are already specified by the user (either on the command line or via
+/// environment variables) are skipped, along with the options that belong to the same group
+/// (e.g. `secret-name` from the profile is not used if user passed `--secret-prefix`). Options
+/// that take multiple values (e.g. `mask`) are replaced as a whole, not appended to.
+fn profile_args(
+    cli: &Command,
+    subcommand: &str,
+    user_matches: Option<&ArgMatches>,
+    profile: &SelectedProfile,
+) -> Result<Vec<String>> {
+    // The arguments are added to their groups only when the command is built.
+    let mut cli = cli.clone();
+    cli.build();
+    let Some(cmd) = cli.find_subcommand(subcommand) else {
+        bail!(ConfigError::SubcommandNotFound(subcommand.to_string()));
+    };
+    let user_provided = |id: &str| {
+        user_matches.is_some_and(|m| {
+            matches!(
+                m.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        })
+    };
+
+    let mut args = Vec::new();
+    for (key, value) in &profile.values {
+        let Some(arg) = cmd.get_arguments().find(|a| a.get_long() == Some(key)) else {
+            let known = cli
+                .get_subcommands()
+                .flat_map(|c| c.get_arguments())
+                .any(|a| a.get_long() == Some(key));
+            if known {
+                continue;
+            }
+            bail!(ConfigError::UnknownOption(
+                profile.name.clone(),
+                key.clone()
+            ));
+        };
+
+        let id = arg.get_id().as_str();
+        let overridden = user_provided(id)
+            || cmd
+                .get_groups()
+                .filter(|g| is_choice(g) && g.get_args().any(|a| a == id))
+                .flat_map(|g| g.get_args())
+                .any(|a| user_provided(a.as_str()));
+        if overridden {
+            continue;
+        }
+
+        let values = value_to_strings(&profile.name, key, value)?;
+        if arg.get_action().takes_values() {
+            args.extend(values.into_iter().map(|v| format!("--{key}={v}")));
+        } else if values.iter().any(|v| v == "true") {
+            args.push(format!("--{key}"));
+        }
+    }
+    Ok(args)
+}
+
+/// Applies the profile selected with `--profile` (or `KVENV_PROFILE`) to the command line.
+///
+/// Profile values are injected right after the subcommand name, so they are validated exactly
+/// like the options specified by the user.
+pub fn apply_profile(args: Vec<OsString>) -> Result<(Vec<OsString>, Option<SelectedProfile>)> {
+    let cli = Cli::command();
+    let Ok(matches) = cli.clone().ignore_errors(true).try_get_matches_from(&args) else {
+        return Ok((args, None));
+    };
+    let (Some(name), Some((subcommand, user_matches))) =
+        (matches.get_one::<String>("profile"), matches.subcommand())
+    else {
+        return Ok((args, None));
+    };
+
+    let profile = load_profile(
+        name,
+        matches.get_one::<PathBuf>("config").map(|p| p.as_path()),
+    )?;
+    let injected = profile_args(&cli, subcommand, Some(user_matches), &profile)?;
+
+    let position = args
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find(|(i, a)| {
+            *a == subcommand && !matches!(args[i - 1].to_str(), Some("--profile" | "--config"))
+        })
+        .map(|(i, _)| i + 1)
+        .ok_or_else(|| ConfigError::SubcommandNotFound(subcommand.to_string()))?;
+    let mut result = args;
+    result.splice(position..position, injected.into_iter().map(OsString::from));
+    Ok((result, Some(profile)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(contents: &str) -> SelectedProfile {
+        let file: ConfigFile = toml::from_str(contents).unwrap();
+        SelectedProfile {
+            name: "test".to_string(),
+            files: vec![],
+            values: file.profiles.into_values().next().unwrap(),
+        }
+    }
+
+    #[test]
+    fn converts_profile_to_args() {
+        let p = profile(
+            r#"
+            [profiles.test]
+            aws = true
+            vault = false
+            aws-region = "eu-central-1"
+            secret-name = "staging/app"
+            mask = ["A", "B"]
+            output-dir = "/tmp"
+            "#,
+        );
+
+        let args = profile_args(&Cli::command(), "run-in", None, &p).unwrap();
+        assert_eq!(
+            vec![
+                "--aws",
+                "--aws-region=eu-central-1",
+                "--mask=A",
+                "--mask=B",
+                "--secret-name=staging/app",
+            ],
+            args
+        );
+    }
+
+    #[test]
+    fn user_values_replace_profile_lists() {
+        let p = profile(
+            r#"
+            [profiles.test]
+            mask = ["A", "B"]
+            secret-name = "staging/app"
+            "#,
+        );
+        let cli = Cli::command();
+        let matches = cli
+            .clone()
+            .ignore_errors(true)
+            .try_get_matches_from(["kvenv", "run-in", "--aws", "--mask", "C", "--", "env"])
+            .unwrap();
+        let (_, user_matches) = matches.subcommand().unwrap();
+
+        let args = profile_args(&cli, "run-in", Some(user_matches), &p).unwrap();
+        assert_eq!(vec!["--secret-name=staging/app"], args);
+
+        let matches = cli
+            .clone()
+            .ignore_errors(true)
+            .try_get_matches_from(["kvenv", "run-in", "--secret-prefix", "app/", "--", "env"])
+            .unwrap();
+        let (_, user_matches) = matches.subcommand().unwrap();
+
+        let args = profile_args(&cli, "run-in", Some(user_matches), &p).unwrap();
+        assert_eq!(vec!["--mask=A", "--mask=B"], args);
+    }
+
+    #[test]
+    fn keeps_profile_backends_along_user_ones() {
+        let p = profile(
+            r#"
+            [profiles.test]
+            aws = true
+            aws-region = "eu-central-1"
+            "#,
+        );
+        let cli = Cli::command();
+        let matches = cli
+            .clone()
+            .ignore_errors(true)
+            .try_get_matches_from([
+                "kvenv",
+                "run-in",
+                "--vault",
+                "--vault-address",
+                "https://vault:8200",
+                "--secret-name",
+                "app",
+                "--",
+                "env",
+            ])
+            .unwrap();
+        let (_, user_matches) = matches.subcommand().unwrap();
+
+        let args = profile_args(&cli, "run-in", Some(user_matches), &p).unwrap();
+        assert_eq!(vec!["--aws", "--aws-region=eu-central-1"], args);
+    }
+
+    #[test]
+    fn fails_on_unknown_option() {
+        let p = profile(
+            r#"
+            [profiles.test]
+            aws-regoin = "eu-central-1"
+            "#,
+        );
+
+        assert!(profile_args(&Cli::command(), "run-in", None, &p).is_err());
+    }
+
+    #[test]
+    fn fails_on_nested_values() {
+        let p = profile(
+            r#"
+            [profiles.test]
+            mask = [["A"]]
+            "#,
+        );
+
+        assert!(profile_args(&Cli::command(), "run-in", None, &p).is_err());
+    }
+
+    #[test]
+    fn finds_project_file_in_parent_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("a").join("b");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(dir.path().join(CONFIG_FILE_NAME), "").unwrap();
+
+        assert_eq!(
+            Some(dir.path().join(CONFIG_FILE_NAME)),
+            find_project_file(&nested)
+        );
+    }
+
+    #[test]
+    fn loads_explicit_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE_NAME);
+        fs::write(
+            &path,
+            "[profiles.staging]\nsecret-name = \"a\"\n[profiles.prod]\nsecret-name = \"b\"\n",
+        )
+        .unwrap();
+
+        let p = load_profile("prod", Some(&path)).unwrap();
+        assert_eq!(
+            Some(&toml::Value::String("b".to_string())),
+            p.values.get("secret-name")
+        );
+        assert!(load_profile("dev", Some(&path)).is_err());
+    }
+}
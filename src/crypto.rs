@@ -0,0 +1,299 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use age::{
+    secrecy::SecretString, x25519, DecryptError, Decryptor, EncryptError, Encryptor, IdentityFile,
+    IdentityFileEntry,
+};
+use clap::{Args, ValueHint};
+use thiserror::Error;
+
+/// Every age-encrypted file starts with this line.
+const AGE_MAGIC: &[u8] = b"age-encryption.org/";
+
+#[derive(Error, Debug)]
+pub enum CryptoError {
+    #[error("cannot read the key file '{0}'")]
+    KeyFile(PathBuf, #[source] io::Error),
+    #[error("the key file '{0}' does not contain any age identity")]
+    EmptyKeyFile(PathBuf),
+    #[error("'{0}' is not a valid age recipient")]
+    InvalidRecipient(String),
+    #[error("cannot encrypt the environment file")]
+    Encrypt(#[source] EncryptError),
+    #[error("the environment file is encrypted, but neither passphrase nor key file was given")]
+    Encrypted,
+    #[error("the environment file is not encrypted, but passphrase or key file was given")]
+    NotEncrypted,
+    #[error("the environment file is encrypted with a different passphrase or key")]
+    WrongKey,
+    #[error("cannot decrypt the environment file - it is corrupted or was tampered with")]
+    Decrypt(#[source] anyhow::Error),
+    #[error("cannot read the environment file")]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T, E = CryptoError> = std::result::Result<T, E>;
+
+/// Encryption of the cached environment file.
+#[derive(Args, Debug, Default)]
+pub struct EncryptionConfig {
+    /// Encrypt the environment file with the passphrase. Cannot be used along `key-file` nor
+    /// `recipient`.
+    #[arg(
+        long,
+        env = "KVENV_PASSPHRASE",
+        hide_env_values = true,
+        conflicts_with_all = ["key_file", "recipient"]
+    )]
+    passphrase: Option<String>,
+
+    /// Encrypt the environment file to the public key of the age identity stored in the file.
+    #[arg(long, env = "KVENV_KEY_FILE", value_parser, value_hint = ValueHint::FilePath)]
+    key_file: Option<PathBuf>,
+
+    /// Encrypt the environment file to the age recipient (an `age1...` X25519 public key). Can be
+    /// specified multiple times.
+    #[arg(long, value_name = "RECIPIENT")]
+    recipient: Vec<String>,
+}
+
+/// Decryption of the cached environment file.
+#[derive(Args, Debug, Default)]
+pub struct DecryptionConfig {
+    /// The passphrase the environment file was encrypted with.
+    #[arg(
+        long,
+        env = "KVENV_PASSPHRASE",
+        hide_env_values = true,
+        conflicts_with = "key_file"
+    )]
+    passphrase: Option<String>,
+
+    /// The file with age identities that the environment file was encrypted to.
+    #[arg(long, env = "KVENV_KEY_FILE", value_parser, value_hint = ValueHint::FilePath)]
+    key_file: Option<PathBuf>,
+}
+
+fn read_identities(path: &Path) -> Result<Vec<x25519::Identity>> {
+    let identities: Vec<_> = IdentityFile::from_file(path.to_string_lossy().into_owned())
+        .map_err(|e| CryptoError::KeyFile(path.to_owned(), e))?
+        .into_identities()
+        .into_iter()
+        .map(|i| match i {
+            IdentityFileEntry::Native(i) => i,
+        })
+        .collect();
+    if identities.is_empty() {
+        return Err(CryptoError::EmptyKeyFile(path.to_owned()));
+    }
+    Ok(identities)
+}
+
+impl EncryptionConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.passphrase.is_some() || self.key_file.is_some() || !self.recipient.is_empty()
+    }
+
+    fn to_encryptor(&self) -> Result<Option<Encryptor>> {
+        if let Some(passphrase) = &self.passphrase {
+            let passphrase = SecretString::new(passphrase.clone());
+            return Ok(Some(Encryptor::with_user_passphrase(passphrase)));
+        }
+
+        let mut recipients: Vec<Box<dyn age::Recipient + Send>> = Vec::new();
+        if let Some(path) = &self.key_file {
+            for identity in read_identities(path)? {
+                recipients.push(Box::new(identity.to_public()));
+            }
+        }
+        for r in &self.recipient {
+            let recipient: x25519::Recipient = r
+                .parse()
+                .map_err(|_| CryptoError::InvalidRecipient(r.clone()))?;
+            recipients.push(Box::new(recipient));
+        }
+        Ok(Encryptor::with_recipients(recipients))
+    }
+
+    /// Writes the data produced by `f` to `w`, encrypting it if encryption is enabled.
+    pub fn write<W, F>(&self, w: W, f: F) -> anyhow::Result<()>
+    where
+        W: Write,
+        F: FnOnce(&mut dyn Write) -> anyhow::Result<()>,
+    {
+        match self.to_encryptor()? {
+            Some(encryptor) => {
+                let mut w = encryptor.wrap_output(w).map_err(CryptoError::Encrypt)?;
+                f(&mut w)?;
+                w.finish()?;
+            }
+            None => {
+                let mut w = w;
+                f(&mut w)?;
+                w.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DecryptionConfig {
+    fn is_enabled(&self) -> bool {
+        self.passphrase.is_some() || self.key_file.is_some()
+    }
+
+    /// Reads the whole (possibly encrypted) environment file.
+    ///
+    /// The contents is decrypted and authenticated completely before it is returned, so a file
+    /// that was tampered with is never partially used.
+    pub fn read<R: Read>(&self, r: R) -> Result<Vec<u8>> {
+        let mut r = BufReader::new(r);
+        let is_encrypted = r.fill_buf()?.starts_with(AGE_MAGIC);
+        let mut result = Vec::new();
+
+        match (is_encrypted, self.is_enabled()) {
+            (false, false) => {
+                r.read_to_end(&mut result)?;
+            }
+            (false, true) => return Err(CryptoError::NotEncrypted),
+            (true, false) => return Err(CryptoError::Encrypted),
+            (true, true) => self.decrypt(r, &mut result)?,
+        }
+        Ok(result)
+    }
+
+    fn decrypt<R: Read>(&self, r: R, out: &mut Vec<u8>) -> Result<()> {
+        let decryptor = Decryptor::new(r).map_err(map_decrypt_error)?;
+        let mut reader = match (decryptor, &self.passphrase, &self.key_file) {
+            (Decryptor::Passphrase(d), Some(passphrase), _) => {
+                let passphrase = SecretString::new(passphrase.clone());
+                d.decrypt(&passphrase, None).map_err(map_decrypt_error)?
+            }
+            (Decryptor::Recipients(d), _, Some(path)) => {
+                let identities = read_identities(path)?;
+                d.decrypt(identities.iter().map(|i| i as &dyn age::Identity))
+                    .map_err(map_decrypt_error)?
+            }
+            _ => return Err(CryptoError::WrongKey),
+        };
+        reader
+            .read_to_end(out)
+            .map_err(|e| CryptoError::Decrypt(e.into()))?;
+        Ok(())
+    }
+}
+
+fn map_decrypt_error(e: DecryptError) -> CryptoError {
+    match e {
+        DecryptError::DecryptionFailed | DecryptError::NoMatchingKeys => CryptoError::WrongKey,
+        e => CryptoError::Decrypt(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use age::secrecy::ExposeSecret;
+
+    use super::*;
+
+    fn write_identity(dir: &Path, name: &str) -> (PathBuf, x25519::Identity) {
+        let identity = x25519::Identity::generate();
+        let path = dir.join(name);
+        std::fs::write(&path, identity.to_string().expose_secret()).unwrap();
+        (path, identity)
+    }
+
+    fn encrypt(cfg: &EncryptionConfig, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        cfg.write(&mut out, |w| Ok(w.write_all(data)?)).unwrap();
+        out
+    }
+
+    #[test]
+    fn plaintext_round_trip() {
+        let data = encrypt(&EncryptionConfig::default(), b"test");
+        assert_eq!(b"test".to_vec(), data);
+        assert_eq!(
+            b"test".to_vec(),
+            DecryptionConfig::default().read(&data[..]).unwrap()
+        );
+    }
+
+    #[test]
+    fn key_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let (key_file, _) = write_identity(dir.path(), "key");
+        let (other_key_file, _) = write_identity(dir.path(), "other");
+
+        let data = encrypt(
+            &EncryptionConfig {
+                key_file: Some(key_file.clone()),
+                ..Default::default()
+            },
+            b"test",
+        );
+        assert!(data.starts_with(AGE_MAGIC));
+
+        let decrypt = |key_file| {
+            DecryptionConfig {
+                passphrase: None,
+                key_file: Some(key_file),
+            }
+            .read(&data[..])
+        };
+        assert_eq!(b"test".to_vec(), decrypt(key_file).unwrap());
+        assert!(matches!(
+            decrypt(other_key_file),
+            Err(CryptoError::WrongKey)
+        ));
+        assert!(matches!(
+            DecryptionConfig::default().read(&data[..]),
+            Err(CryptoError::Encrypted)
+        ));
+    }
+
+    #[test]
+    fn recipient_encryption_detects_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let (key_file, identity) = write_identity(dir.path(), "key");
+
+        let mut data = encrypt(
+            &EncryptionConfig {
+                recipient: vec![identity.to_public().to_string()],
+                ..Default::default()
+            },
+            b"test",
+        );
+        let last = data.len() - 1;
+        data[last] ^= 1;
+
+        let result = DecryptionConfig {
+            passphrase: None,
+            key_file: Some(key_file),
+        }
+        .read(&data[..]);
+        assert!(matches!(result, Err(CryptoError::Decrypt(_))));
+    }
+
+    #[test]
+    fn rejects_plaintext_when_key_is_given() {
+        let result = DecryptionConfig {
+            passphrase: Some("pass".to_string()),
+            key_file: None,
+        }
+        .read(&b"{}"[..]);
+        assert!(matches!(result, Err(CryptoError::NotEncrypted)));
+    }
+
+    #[test]
+    fn rejects_invalid_recipient() {
+        let cfg = EncryptionConfig {
+            recipient: vec!["age1invalid".to_string()],
+            ..Default::default()
+        };
+        assert!(cfg.write(Vec::new(), |_| Ok(())).is_err());
+    }
+}
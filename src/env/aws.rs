@@ -1,17 +1,25 @@
-use clap::{arg, Args};
+use std::{cell::RefCell, path::PathBuf};
+
+use clap::Args;
 use futures::future::try_join_all;
+use hyper_rustls::HttpsConnectorBuilder;
 use rusoto_core::{request::TlsError, HttpClient, Region};
 use rusoto_credential::{CredentialsError, DefaultCredentialsProvider, StaticProvider};
 use rusoto_secretsmanager::{
-    GetSecretValueError, GetSecretValueRequest, GetSecretValueResponse, ListSecretsError,
+    Filter, GetSecretValueError, GetSecretValueRequest, GetSecretValueResponse, ListSecretsError,
     ListSecretsRequest, SecretsManager, SecretsManagerClient,
 };
 use serde_json::Value;
 use thiserror::Error;
 
+mod credentials;
+
+pub use credentials::AwsCredentialsProvider;
+use credentials::Config;
+
 use super::{
     convert::{convert_env_name, decode_env_from_json},
-    Vault, VaultConfig,
+    SecretVersion, Vault, VaultConfig,
 };
 
 #[derive(Args, Debug)]
@@ -26,6 +34,23 @@ pub struct AwsConfig {
     )]
     enabled: bool,
 
+    #[command(flatten)]
+    pub credentials: AwsCredentialsConfig,
+
+    /// [AWS] The staging label of the secret versions to download, e.g. `AWSPREVIOUS`. Defaults
+    /// to `AWSCURRENT`.
+    #[arg(long, display_order = 104)]
+    aws_version_stage: Option<String>,
+
+    /// [AWS] The exact version of the secret to download. Can be used only with `secret-name`.
+    #[arg(long, conflicts_with = "aws_version_stage", display_order = 105)]
+    aws_version_id: Option<String>,
+}
+
+/// The credentials, region and endpoint, kept apart from the Secrets Manager options so they can
+/// be shared with other AWS services.
+#[derive(Args, Debug, Clone)]
+pub struct AwsCredentialsConfig {
     /// [AWS] The Access Key Id. Requires `secret_access_key` if provided. If not specified,
     /// default rusoto credential matching is used.
     #[arg(
@@ -46,16 +71,62 @@ pub struct AwsConfig {
     )]
     aws_secret_access_key: Option<String>,
 
+    /// [AWS] The session token of temporary credentials. Used only with `access_key_id`.
+    #[arg(
+        long,
+        env = "AWS_SESSION_TOKEN",
+        hide_env_values = true,
+        display_order = 103
+    )]
+    aws_session_token: Option<String>,
+
+    /// [AWS] The profile from the AWS CLI configuration to use. Supports static credentials,
+    /// `credential_process`, AWS SSO and assuming a role with `source_profile`.
+    #[arg(long, env = "AWS_PROFILE", display_order = 106)]
+    aws_profile: Option<String>,
+
+    /// [AWS] The file with the web identity token (e.g. on EKS) to assume
+    /// `web-identity-role-arn` with.
+    #[arg(long, env = "AWS_WEB_IDENTITY_TOKEN_FILE", display_order = 107)]
+    aws_web_identity_token_file: Option<PathBuf>,
+
+    /// [AWS] The role to assume with the web identity token. Required with
+    /// `web-identity-token-file`.
+    #[arg(long, env = "AWS_ROLE_ARN", display_order = 108)]
+    aws_web_identity_role_arn: Option<String>,
+
+    /// [AWS] The role to assume with the credentials, e.g. in another account.
+    #[arg(long, display_order = 109)]
+    aws_role_arn: Option<String>,
+
+    /// [AWS] The external id required by the role.
+    #[arg(long, requires = "aws_role_arn", display_order = 110)]
+    aws_external_id: Option<String>,
+
+    /// [AWS] The name of the session when assuming the role.
+    #[arg(
+        long,
+        env = "AWS_ROLE_SESSION_NAME",
+        default_value = "kvenv",
+        display_order = 111
+    )]
+    aws_role_session_name: String,
+
     /// [AWS] AWS region.
     #[arg(long, env = "AWS_REGION", display_order = 122)]
     aws_region: Option<Region>,
+
+    /// [AWS] The URL of the endpoint to use instead of the default one, e.g. a VPC interface
+    /// endpoint or LocalStack.
+    #[arg(long, env = "AWS_ENDPOINT_URL", display_order = 123)]
+    aws_endpoint_url: Option<String>,
 }
 
 #[derive(Error, Debug)]
 pub enum AwsError {
     #[error("rusoto HttpClient error")]
     TlsError(#[source] TlsError),
-    #[error("rusoto HttpClient error")]
+    #[error("cannot load AWS credentials")]
     CredentialsError(#[source] CredentialsError),
     #[error("cannot load secret from Secrets Manager")]
     GetSecretError(#[source] rusoto_core::RusotoError<GetSecretValueError>),
@@ -67,14 +138,26 @@ pub enum AwsError {
     ListSecretsError(#[source] rusoto_core::RusotoError<ListSecretsError>),
     #[error("cannot decode secret - it is not a valid JSON object")]
     DecodeError(#[source] serde_json::Error),
-    #[error("there are no secrets in the Secrets Manager")]
-    NoSecrets,
+    #[error("there are no secrets with the prefix '{0}' in the Secrets Manager")]
+    NoMatchingSecrets(String),
+    #[error(
+        "`--aws-version-id` cannot be used in prefixed mode, as versions differ between secrets"
+    )]
+    VersionIdWithPrefix,
+    #[error(
+        "the web identity token file is set, but the role to assume with it is not - set \
+        `--aws-web-identity-role-arn` or `AWS_ROLE_ARN`"
+    )]
+    MissingWebIdentityRoleArn,
 }
 
 pub type Result<T, E = AwsError> = std::result::Result<T, E>;
 
 pub struct AwsVault {
     client: SecretsManagerClient,
+    version_stage: Option<String>,
+    version_id: Option<String>,
+    versions: RefCell<Vec<SecretVersion>>,
 }
 
 impl VaultConfig for AwsConfig {
@@ -84,88 +167,246 @@ impl VaultConfig for AwsConfig {
         self.enabled
     }
 
+    fn origin_args(&self) -> Vec<String> {
+        let mut args = vec!["--aws".to_string()];
+        if let Some(stage) = &self.aws_version_stage {
+            args.extend(["--aws-version-stage".to_string(), stage.clone()]);
+        }
+        if let Some(id) = &self.aws_version_id {
+            args.extend(["--aws-version-id".to_string(), id.clone()]);
+        }
+        args
+    }
+
     fn into_vault(self) -> anyhow::Result<Self::Vault> {
-        let http_client = HttpClient::new().map_err(AwsError::TlsError)?;
-        if let Some(key_id) = self.aws_access_key_id {
+        let connection = self.credentials.connect(None)?;
+        Ok(Self::Vault {
+            client: SecretsManagerClient::new_with(
+                connection.http_client,
+                connection.provider,
+                connection.region,
+            ),
+            version_stage: self.aws_version_stage,
+            version_id: self.aws_version_id,
+            versions: RefCell::default(),
+        })
+    }
+}
+
+/// What the clients of the AWS services are created with.
+pub struct AwsConnection {
+    pub http_client: HttpClient,
+    pub provider: AwsCredentialsProvider,
+    pub region: Region,
+}
+
+impl AwsCredentialsConfig {
+    /// Non-secret arguments that recreate the configuration, like `VaultConfig::origin_args`.
+    pub fn origin_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(region) = &self.aws_region {
+            args.extend(["--aws-region".to_string(), region.name().to_string()]);
+        }
+        if let Some(url) = &self.aws_endpoint_url {
+            args.extend(["--aws-endpoint-url".to_string(), url.clone()]);
+        }
+        if let Some(profile) = &self.aws_profile {
+            args.extend(["--aws-profile".to_string(), profile.clone()]);
+        }
+        if let Some(file) = &self.aws_web_identity_token_file {
+            args.extend([
+                "--aws-web-identity-token-file".to_string(),
+                file.to_string_lossy().into_owned(),
+            ]);
+        }
+        if let Some(role) = &self.aws_web_identity_role_arn {
+            args.extend(["--aws-web-identity-role-arn".to_string(), role.clone()]);
+        }
+        if let Some(role) = &self.aws_role_arn {
+            args.extend(["--aws-role-arn".to_string(), role.clone()]);
+        }
+        if let Some(id) = &self.aws_external_id {
+            args.extend(["--aws-external-id".to_string(), id.clone()]);
+        }
+        if self.aws_role_arn.is_some() || self.aws_web_identity_token_file.is_some() {
+            args.extend([
+                "--aws-role-session-name".to_string(),
+                self.aws_role_session_name.clone(),
+            ]);
+        }
+        args
+    }
+
+    /// Resolves the credentials. The service is called at `endpoint_url` if given, falling back
+    /// to `aws-endpoint-url`.
+    pub fn connect(mut self, endpoint_url: Option<String>) -> Result<AwsConnection> {
+        let region = self.aws_region.clone().unwrap();
+        let endpoint_url = endpoint_url.or(self.aws_endpoint_url.take());
+        let provider = self.provider(&region, credentials::load_config)?;
+
+        // Only the service uses the endpoint, STS and SSO are still called in the region.
+        let (http_client, region) = match endpoint_url {
+            Some(endpoint) => {
+                // Local stand-ins (like LocalStack) are usually served over plain HTTP.
+                let connector = HttpsConnectorBuilder::new()
+                    .with_native_roots()
+                    .https_or_http()
+                    .enable_http2()
+                    .build();
+                let region = Region::Custom {
+                    name: region.name().to_string(),
+                    endpoint,
+                };
+                (HttpClient::from_connector(connector), region)
+            }
+            None => (HttpClient::new().map_err(AwsError::TlsError)?, region),
+        };
+        Ok(AwsConnection {
+            http_client,
+            provider,
+            region,
+        })
+    }
+
+    /// Picks the credentials provider. The CLI config is loaded only if a profile is used.
+    fn provider(
+        self,
+        region: &Region,
+        load_config: impl FnOnce() -> Config,
+    ) -> Result<AwsCredentialsProvider> {
+        let session_name = self.aws_role_session_name;
+        let provider = if let Some(key_id) = self.aws_access_key_id {
             let secret = self.aws_secret_access_key.unwrap();
-            let provider = StaticProvider::new_minimal(key_id, secret);
-            Ok(Self::Vault {
-                client: SecretsManagerClient::new_with(
-                    http_client,
-                    provider,
-                    self.aws_region.unwrap(),
-                ),
-            })
+            AwsCredentialsProvider::Static(StaticProvider::new(
+                key_id,
+                secret,
+                self.aws_session_token,
+                None,
+            ))
+        } else if let Some(profile) = &self.aws_profile {
+            // The profile goes before the web identity, as the token file is often set by the
+            // environment (e.g. on EKS), like the AWS SDKs do.
+            AwsCredentialsProvider::profile(&load_config(), region, profile)
+                .map_err(AwsError::CredentialsError)?
+        } else if let Some(token_file) = self.aws_web_identity_token_file {
+            AwsCredentialsProvider::web_identity(
+                token_file,
+                self.aws_web_identity_role_arn
+                    .ok_or(AwsError::MissingWebIdentityRoleArn)?,
+                session_name.clone(),
+            )
+            .map_err(AwsError::CredentialsError)?
         } else {
             let provider = DefaultCredentialsProvider::new().map_err(AwsError::CredentialsError)?;
-            Ok(Self::Vault {
-                client: SecretsManagerClient::new_with(
-                    http_client,
-                    provider,
-                    self.aws_region.unwrap(),
-                ),
-            })
+            AwsCredentialsProvider::Default(Box::new(provider))
+        };
+        match self.aws_role_arn {
+            Some(role_arn) => provider
+                .assume_role(region.clone(), role_arn, self.aws_external_id, session_name)
+                .map_err(AwsError::CredentialsError),
+            None => Ok(provider),
         }
     }
 }
 
-impl Vault for AwsVault {
-    #[tokio::main]
-    async fn download_prefixed(&self, prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
-        let list = self
+/// The server-side filter for the secrets with the prefix. The `name` filter is not
+/// case-sensitive, so the names still need to be checked.
+fn name_filters(prefix: &str) -> Option<Vec<Filter>> {
+    (!prefix.is_empty()).then(|| {
+        vec![Filter {
+            key: Some("name".to_string()),
+            values: Some(vec![prefix.to_string()]),
+        }]
+    })
+}
+
+impl AwsVault {
+    /// Gets the configured version of the secret and records which version it was.
+    async fn get_secret(&self, name: &str) -> Result<GetSecretValueResponse> {
+        let secret = self
             .client
-            .list_secrets(ListSecretsRequest {
-                max_results: Some(100),
-                ..Default::default()
+            .get_secret_value(GetSecretValueRequest {
+                secret_id: name.to_string(),
+                version_id: self.version_id.clone(),
+                version_stage: self.version_stage.clone(),
             })
             .await
-            .map_err(AwsError::ListSecretsError)?;
-        let results = list
-            .secret_list
-            .ok_or(AwsError::NoSecrets)?
-            .into_iter()
-            .filter(|x| {
-                x.name
-                    .as_ref()
-                    .map(|n| n.starts_with(prefix))
-                    .unwrap_or(false)
-            })
-            .map(|s| async {
-                let name = s.name.unwrap();
-                let secret = self
-                    .client
-                    .get_secret_value(GetSecretValueRequest {
-                        secret_id: name.clone(),
-                        version_id: None,
-                        version_stage: None,
-                    })
-                    .await
-                    .map_err(AwsError::GetSecretError)?;
-                let value = secret
-                    .secret_string
-                    .ok_or_else(|| AwsError::NoStringData(name.clone()))?;
-                let name = convert_env_name(prefix, &name)
-                    .map_err(|_| AwsError::InvalidSecretName(name.clone()))?;
-                Ok::<_, AwsError>((name, value))
+            .map_err(AwsError::GetSecretError)?;
+        if let Some(version_id) = &secret.version_id {
+            self.versions.borrow_mut().push(SecretVersion {
+                secret: name.to_string(),
+                version_id: version_id.clone(),
+                stages: secret.version_stages.clone().unwrap_or_default(),
             });
+        }
+        Ok(secret)
+    }
+
+    /// Lists the names of all the secrets with the prefix, going through all the pages.
+    async fn list_secret_names(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut next_token = None;
+        loop {
+            let list = self
+                .client
+                .list_secrets(ListSecretsRequest {
+                    filters: name_filters(prefix),
+                    max_results: Some(100),
+                    next_token,
+                    ..Default::default()
+                })
+                .await
+                .map_err(AwsError::ListSecretsError)?;
+            names.extend(
+                list.secret_list
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|s| s.name)
+                    .filter(|n| n.starts_with(prefix)),
+            );
+
+            next_token = list.next_token;
+            if next_token.is_none() {
+                return Ok(names);
+            }
+        }
+    }
+}
+
+impl Vault for AwsVault {
+    #[tokio::main]
+    async fn download_prefixed(&self, prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
+        if self.version_id.is_some() {
+            return Err(AwsError::VersionIdWithPrefix.into());
+        }
+        let names = self.list_secret_names(prefix).await?;
+        if names.is_empty() {
+            return Err(AwsError::NoMatchingSecrets(prefix.to_string()).into());
+        }
+
+        let results = names.into_iter().map(|name| async move {
+            let secret = self.get_secret(&name).await?;
+            let value = secret
+                .secret_string
+                .ok_or_else(|| AwsError::NoStringData(name.clone()))?;
+            let name = convert_env_name(prefix, &name)
+                .map_err(|_| AwsError::InvalidSecretName(name.clone()))?;
+            Ok::<_, AwsError>((name, value))
+        });
         let values: Vec<_> = try_join_all(results).await?.into_iter().collect();
         Ok(values)
     }
 
     #[tokio::main]
     async fn download_json(&self, secret_name: &str) -> anyhow::Result<Vec<(String, String)>> {
-        let secret = self
-            .client
-            .get_secret_value(GetSecretValueRequest {
-                secret_id: secret_name.to_string(),
-                version_id: None,
-                version_stage: None,
-            })
-            .await
-            .map_err(AwsError::GetSecretError)?;
+        let secret = self.get_secret(secret_name).await?;
         let value = decode_secret(secret)?;
         decode_env_from_json(secret_name, value)
     }
+
+    fn take_versions(&self) -> Vec<SecretVersion> {
+        self.versions.take()
+    }
 }
 
 fn decode_secret(secret: GetSecretValueResponse) -> Result<Value> {
@@ -180,8 +421,147 @@ fn decode_secret(secret: GetSecretValueResponse) -> Result<Value> {
 
 #[cfg(test)]
 mod tests {
-    #[cfg(feature = "integration-tests")]
     use super::*;
+    use clap::FromArgMatches;
+    use rusoto_core::signature::{SignedRequest, SignedRequestPayload};
+    use rusoto_mock::{
+        MockCredentialsProvider, MockRequestDispatcher, MultipleMockRequestDispatcher,
+    };
+    use serde_json::json;
+
+    fn mocked_vault(responses: Vec<MockRequestDispatcher>) -> AwsVault {
+        AwsVault {
+            client: SecretsManagerClient::new_with(
+                MultipleMockRequestDispatcher::new(responses),
+                MockCredentialsProvider,
+                Region::EuCentral1,
+            ),
+            version_stage: None,
+            version_id: None,
+            versions: RefCell::default(),
+        }
+    }
+
+    fn request_body(request: &SignedRequest) -> Value {
+        match &request.payload {
+            Some(SignedRequestPayload::Buffer(body)) => serde_json::from_slice(body).unwrap(),
+            _ => panic!("the request should have a body"),
+        }
+    }
+
+    fn secret_list(names: &[&str], next_token: Option<&str>) -> Value {
+        let secrets: Vec<_> = names.iter().map(|n| json!({ "Name": n })).collect();
+        json!({ "SecretList": secrets, "NextToken": next_token })
+    }
+
+    #[test]
+    fn filters_names_server_side() {
+        assert_eq!(None, name_filters(""));
+        assert_eq!(
+            Some(vec![Filter {
+                key: Some("name".to_string()),
+                values: Some(vec!["app/prod-".to_string()]),
+            }]),
+            name_filters("app/prod-")
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_secret_names_from_all_pages() {
+        let vault = mocked_vault(vec![
+            MockRequestDispatcher::default()
+                .with_json_body(secret_list(&["app-a", "APP-b"], Some("page-2")))
+                .with_request_checker(|r| {
+                    let body = request_body(r);
+                    assert_eq!(
+                        json!([{ "Key": "name", "Values": ["app-"] }]),
+                        body["Filters"]
+                    );
+                    assert_eq!(100, body["MaxResults"]);
+                    assert_eq!(Value::Null, body["NextToken"]);
+                }),
+            MockRequestDispatcher::default()
+                .with_json_body(secret_list(&["app-c"], None))
+                .with_request_checker(|r| assert_eq!("page-2", request_body(r)["NextToken"])),
+        ]);
+
+        // `APP-b` matches the server-side filter, as it ignores the case, but not the prefix.
+        assert_eq!(
+            vec!["app-a".to_string(), "app-c".to_string()],
+            vault.list_secret_names("app-").await.unwrap()
+        );
+    }
+
+    #[test]
+    fn fails_without_matching_secrets() {
+        let vault = mocked_vault(vec![
+            MockRequestDispatcher::default().with_json_body(secret_list(&["APP-a"], None))
+        ]);
+
+        assert!(matches!(
+            vault
+                .download_prefixed("app-")
+                .unwrap_err()
+                .downcast::<AwsError>(),
+            Ok(AwsError::NoMatchingSecrets(prefix)) if prefix == "app-"
+        ));
+    }
+
+    fn web_identity_config() -> AwsCredentialsConfig {
+        AwsCredentialsConfig {
+            aws_access_key_id: None,
+            aws_secret_access_key: None,
+            aws_session_token: None,
+            aws_profile: Some("kvenv-test".to_string()),
+            aws_web_identity_token_file: Some(PathBuf::from("/var/run/secrets/token")),
+            aws_web_identity_role_arn: Some("arn:aws:iam::123456789012:role/pod".to_string()),
+            aws_role_arn: None,
+            aws_external_id: None,
+            aws_role_session_name: "kvenv".to_string(),
+            aws_region: Some(Region::EuCentral1),
+            aws_endpoint_url: None,
+        }
+    }
+
+    #[test]
+    fn prefers_profile_over_web_identity() {
+        let config = || {
+            credentials::parse_config(
+                "[profile kvenv-test]\nsso_account_id = 123456789012\nsso_role_name = app\n\
+                sso_region = eu-west-1\nsso_start_url = https://corp.awsapps.com/start\n",
+            )
+        };
+
+        let provider = web_identity_config()
+            .provider(&Region::EuCentral1, config)
+            .unwrap();
+        assert!(matches!(provider, AwsCredentialsProvider::Sso(_)));
+
+        let provider = AwsCredentialsConfig {
+            aws_profile: None,
+            ..web_identity_config()
+        }
+        .provider(&Region::EuCentral1, || panic!("the config is not needed"))
+        .unwrap();
+        assert!(matches!(provider, AwsCredentialsProvider::WebIdentity(_)));
+    }
+
+    #[test]
+    fn requires_role_for_web_identity_only_when_used() {
+        let cmd = AwsCredentialsConfig::augment_args(clap::Command::new("kvenv"));
+        let matches = cmd
+            .try_get_matches_from(["kvenv", "--aws-web-identity-token-file", "/token"])
+            .unwrap();
+        let cfg = AwsCredentialsConfig {
+            aws_web_identity_role_arn: None,
+            ..AwsCredentialsConfig::from_arg_matches(&matches).unwrap()
+        };
+
+        assert!(matches!(
+            cfg.provider(&Region::EuCentral1, Config::new),
+            Err(AwsError::MissingWebIdentityRoleArn)
+        ));
+    }
 
     #[cfg(feature = "integration-tests")]
     macro_rules! env {
@@ -199,9 +579,21 @@ mod tests {
         use std::env::var as env_var;
         let cfg = AwsConfig {
             enabled: true,
-            aws_access_key_id: Some(env_var("AWS_ACCESS_KEY_ID").unwrap()),
-            aws_secret_access_key: Some(env_var("AWS_SECRET_ACCESS_KEY").unwrap()),
-            aws_region: Some(Region::EuCentral1),
+            credentials: AwsCredentialsConfig {
+                aws_access_key_id: Some(env_var("AWS_ACCESS_KEY_ID").unwrap()),
+                aws_secret_access_key: Some(env_var("AWS_SECRET_ACCESS_KEY").unwrap()),
+                aws_session_token: None,
+                aws_profile: None,
+                aws_web_identity_token_file: None,
+                aws_web_identity_role_arn: None,
+                aws_role_arn: None,
+                aws_external_id: None,
+                aws_role_session_name: "kvenv".to_string(),
+                aws_region: Some(Region::EuCentral1),
+                aws_endpoint_url: env_var("AWS_ENDPOINT_URL").ok(),
+            },
+            aws_version_stage: None,
+            aws_version_id: None,
         };
         let proc_env = cfg
             .into_vault()
@@ -223,9 +615,21 @@ mod tests {
         use std::env::var as env_var;
         let cfg = AwsConfig {
             enabled: true,
-            aws_access_key_id: Some(env_var("AWS_ACCESS_KEY_ID").unwrap()),
-            aws_secret_access_key: Some(env_var("AWS_SECRET_ACCESS_KEY").unwrap()),
-            aws_region: Some(Region::EuCentral1),
+            credentials: AwsCredentialsConfig {
+                aws_access_key_id: Some(env_var("AWS_ACCESS_KEY_ID").unwrap()),
+                aws_secret_access_key: Some(env_var("AWS_SECRET_ACCESS_KEY").unwrap()),
+                aws_session_token: None,
+                aws_profile: None,
+                aws_web_identity_token_file: None,
+                aws_web_identity_role_arn: None,
+                aws_role_arn: None,
+                aws_external_id: None,
+                aws_role_session_name: "kvenv".to_string(),
+                aws_region: Some(Region::EuCentral1),
+                aws_endpoint_url: env_var("AWS_ENDPOINT_URL").ok(),
+            },
+            aws_version_stage: None,
+            aws_version_id: None,
         };
         let proc_env = cfg
             .into_vault()
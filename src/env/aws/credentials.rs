@@ -0,0 +1,473 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use rusoto_core::{Client, HttpClient, Region};
+use rusoto_credential::{
+    AutoRefreshingProvider, AwsCredentials, CredentialsError, DefaultCredentialsProvider,
+    ProfileProvider, ProvideAwsCredentials, Secret, StaticProvider, Variable,
+};
+use rusoto_sso::{GetRoleCredentialsRequest, Sso, SsoClient};
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient, WebIdentityProvider};
+use serde::Deserialize;
+
+type Result<T, E = CredentialsError> = std::result::Result<T, E>;
+
+/// The credentials used to access AWS. The providers of rusoto are used for everything but the
+/// profiles that assume a role or use AWS SSO, which rusoto cannot read from the CLI config.
+pub enum AwsCredentialsProvider {
+    Static(StaticProvider),
+    Default(Box<DefaultCredentialsProvider>),
+    Profile(ProfileProvider),
+    Sso(AutoRefreshingProvider<SsoProvider>),
+    WebIdentity(AutoRefreshingProvider<WebIdentityProvider>),
+    AssumeRole(AutoRefreshingProvider<StsAssumeRoleSessionCredentialsProvider>),
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for AwsCredentialsProvider {
+    async fn credentials(&self) -> Result<AwsCredentials> {
+        match self {
+            Self::Static(p) => p.credentials().await,
+            Self::Default(p) => p.credentials().await,
+            Self::Profile(p) => p.credentials().await,
+            Self::Sso(p) => p.credentials().await,
+            Self::WebIdentity(p) => p.credentials().await,
+            Self::AssumeRole(p) => p.credentials().await,
+        }
+    }
+}
+
+impl AwsCredentialsProvider {
+    /// Assumes the role with the web identity token (e.g. on EKS or from GitHub Actions OIDC).
+    /// The token is read again every time the credentials are refreshed, as it is rotated.
+    pub fn web_identity(
+        token_file: PathBuf,
+        role_arn: String,
+        session_name: String,
+    ) -> Result<Self> {
+        let token = Variable::dynamic(move || read_token(&token_file).map(Secret::from));
+        let provider = WebIdentityProvider::new(token, role_arn, Some(Some(session_name)));
+        Ok(Self::WebIdentity(AutoRefreshingProvider::new(provider)?))
+    }
+
+    /// Assumes the role using the current credentials.
+    pub fn assume_role(
+        self,
+        region: Region,
+        role_arn: String,
+        external_id: Option<String>,
+        session_name: String,
+    ) -> Result<Self> {
+        let client = StsClient::new_with(http_client()?, self, region);
+        let provider = StsAssumeRoleSessionCredentialsProvider::new(
+            client,
+            role_arn,
+            session_name,
+            external_id,
+            None,
+            None,
+            None,
+        );
+        Ok(Self::AssumeRole(AutoRefreshingProvider::new(provider)?))
+    }
+
+    /// Uses the named profile from the AWS CLI configuration (see `load_config`). Apart from the
+    /// credentials (and `credential_process`) supported by rusoto, the profile can use AWS SSO or
+    /// assume a role with the credentials of its `source_profile`.
+    pub fn profile(config: &Config, region: &Region, profile: &str) -> Result<Self> {
+        Self::from_config(config, region, profile, 0)
+    }
+
+    fn from_config(config: &Config, region: &Region, profile: &str, depth: usize) -> Result<Self> {
+        // The AWS CLI stops at the same depth when following the `source_profile`s.
+        const MAX_DEPTH: usize = 5;
+
+        let empty = HashMap::new();
+        let settings = config.get(profile).unwrap_or(&empty);
+        if let Some(account_id) = settings.get("sso_account_id") {
+            let session = settings
+                .get("sso_session")
+                .and_then(|s| config.get(&format!("sso-session {s}")))
+                .unwrap_or(settings);
+            let get = |key: &str| {
+                session
+                    .get(key)
+                    .or_else(|| settings.get(key))
+                    .ok_or_else(|| {
+                        CredentialsError::new(format!("the profile '{profile}' does not set {key}"))
+                    })
+            };
+            let region = get("sso_region")?
+                .parse()
+                .map_err(|e| CredentialsError::new(format!("invalid `sso_region`: {e}")))?;
+            let provider = SsoProvider {
+                client: SsoClient::new_with_client(Client::new_not_signing(http_client()?), region),
+                start_url: get("sso_start_url")?.clone(),
+                account_id: account_id.clone(),
+                role_name: get("sso_role_name")?.clone(),
+                profile: profile.to_string(),
+            };
+            Ok(Self::Sso(AutoRefreshingProvider::new(provider)?))
+        } else if let Some(role_arn) = settings.get("role_arn") {
+            let base = match settings.get("source_profile") {
+                Some(source) if source == profile => Self::static_profile(profile)?,
+                Some(_) if depth >= MAX_DEPTH => {
+                    return Err(CredentialsError::new(format!(
+                        "too many chained `source_profile`s in the profile '{profile}'"
+                    )))
+                }
+                Some(source) => Self::from_config(config, region, source, depth + 1)?,
+                None => Self::Default(Box::new(DefaultCredentialsProvider::new()?)),
+            };
+            base.assume_role(
+                region.clone(),
+                role_arn.clone(),
+                settings.get("external_id").cloned(),
+                settings
+                    .get("role_session_name")
+                    .cloned()
+                    .unwrap_or_else(|| "kvenv".to_string()),
+            )
+        } else {
+            Self::static_profile(profile)
+        }
+    }
+
+    fn static_profile(profile: &str) -> Result<Self> {
+        ProfileProvider::with_default_credentials(profile).map(Self::Profile)
+    }
+}
+
+fn http_client() -> Result<HttpClient> {
+    HttpClient::new().map_err(|e| CredentialsError::new(format!("cannot create HTTP client: {e}")))
+}
+
+/// The settings of the profiles (by their names) and SSO sessions (as `sso-session NAME`).
+pub type Config = HashMap<String, HashMap<String, String>>;
+
+/// Reads the CLI config from `AWS_CONFIG_FILE` or `~/.aws/config`. A missing file is the same as
+/// an empty one.
+pub fn load_config() -> Config {
+    config_file()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|config| parse_config(&config))
+        .unwrap_or_default()
+}
+
+fn config_file() -> Option<PathBuf> {
+    std::env::var_os("AWS_CONFIG_FILE")
+        .map(PathBuf::from)
+        .or_else(|| aws_dir().map(|d| d.join("config")))
+}
+
+fn aws_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(|h| PathBuf::from(h).join(".aws"))
+}
+
+/// Parses the CLI config. Only `[default]`, `[profile NAME]` and `[sso-session NAME]` sections
+/// are read, like the CLI does, and the nested settings (the indented lines, e.g. of `s3`) are
+/// skipped, as none of them are needed.
+pub fn parse_config(config: &str) -> Config {
+    let mut result = Config::new();
+    let mut section = None;
+    for line in config.lines() {
+        let nested = line.starts_with([' ', '\t']);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(['#', ';']) || nested {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let name = match name.split_whitespace().collect::<Vec<_>>()[..] {
+                ["default"] => Some("default".to_string()),
+                ["profile", name] => Some(name.to_string()),
+                ["sso-session", name] => Some(format!("sso-session {name}")),
+                _ => None,
+            };
+            section = name.map(|n| result.entry(n).or_default());
+        } else if let (Some(settings), Some((key, value))) = (&mut section, line.split_once('=')) {
+            settings.insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+    result
+}
+
+fn read_token(path: &Path) -> Result<String> {
+    fs::read_to_string(path)
+        .map(|t| t.trim().to_string())
+        .map_err(|e| {
+            CredentialsError::new(format!(
+                "cannot read the web identity token from {}: {e}",
+                path.display()
+            ))
+        })
+}
+
+/// Gets the credentials of the role with the token of an AWS SSO session that was started with
+/// `aws sso login`.
+pub struct SsoProvider {
+    client: SsoClient,
+    start_url: String,
+    account_id: String,
+    role_name: String,
+    profile: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedToken {
+    start_url: Option<String>,
+    access_token: Option<String>,
+    expires_at: Option<String>,
+}
+
+impl CachedToken {
+    fn is_valid_for(&self, start_url: &str, now: DateTime<Utc>) -> bool {
+        // The older versions of the CLI wrote the time as `2023-01-01T10:00:00UTC`.
+        let expires_at = self
+            .expires_at
+            .as_ref()
+            .and_then(|e| DateTime::parse_from_rfc3339(&e.replace("UTC", "Z")).ok());
+        self.start_url.as_deref() == Some(start_url)
+            && self.access_token.is_some()
+            && expires_at.is_some_and(|e| e > now)
+    }
+}
+
+/// Finds the token of the session in the SSO cache of the CLI. The cached files are named by
+/// the hash of the session, but they also contain the start URL, so it is enough to go through
+/// all of them.
+fn find_sso_token(cache: &Path, start_url: &str) -> Option<String> {
+    let now = Utc::now();
+    fs::read_dir(cache)
+        .ok()?
+        .filter_map(|e| fs::read(e.ok()?.path()).ok())
+        .filter_map(|c| serde_json::from_slice::<CachedToken>(&c).ok())
+        .find(|t| t.is_valid_for(start_url, now))
+        .and_then(|t| t.access_token)
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for SsoProvider {
+    async fn credentials(&self) -> Result<AwsCredentials> {
+        let token = aws_dir()
+            .and_then(|d| find_sso_token(&d.join("sso").join("cache"), &self.start_url))
+            .ok_or_else(|| {
+                CredentialsError::new(format!(
+                    "the SSO session has expired or was not started, \
+                    run `aws sso login --profile {}`",
+                    self.profile
+                ))
+            })?;
+
+        let request = GetRoleCredentialsRequest {
+            access_token: token,
+            account_id: self.account_id.clone(),
+            role_name: self.role_name.clone(),
+        };
+        let credentials = self
+            .client
+            .get_role_credentials(request)
+            .await
+            .map_err(|e| CredentialsError::new(format!("cannot call AWS SSO: {e}")))?
+            .role_credentials
+            .ok_or_else(|| CredentialsError::new("the AWS SSO response has no credentials"))?;
+        let missing = |field| CredentialsError::new(format!("the AWS SSO response has no {field}"));
+        Ok(AwsCredentials::new(
+            credentials
+                .access_key_id
+                .ok_or_else(|| missing("access key"))?,
+            credentials
+                .secret_access_key
+                .ok_or_else(|| missing("secret access key"))?,
+            credentials.session_token,
+            credentials
+                .expiration
+                .and_then(|e| Utc.timestamp_millis_opt(e).single()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_profiles() {
+        let config = parse_config(
+            "[default]\nregion = eu-central-1\n\n\
+            # comment\n[profile prod]\nrole_arn = arn:aws:iam::123:role/app\n\
+            source_profile=default\n[sso-session corp]\nsso_start_url = https://corp/start\n",
+        );
+        assert_eq!("eu-central-1", config["default"]["region"]);
+        assert_eq!("arn:aws:iam::123:role/app", config["prod"]["role_arn"]);
+        assert_eq!("default", config["prod"]["source_profile"]);
+        assert_eq!(
+            "https://corp/start",
+            config["sso-session corp"]["sso_start_url"]
+        );
+    }
+
+    #[test]
+    fn skips_sections_and_settings_the_cli_ignores() {
+        let config = parse_config(
+            "[prod]
+role_arn = wrong
+[profile dev]
+s3 =
+  region = wrong
+region = eu-west-1
+            ; comment
+[profile  spaced ]
+region = us-east-1
+",
+        );
+        assert!(!config.contains_key("prod"));
+        assert_eq!("eu-west-1", config["dev"]["region"]);
+        assert_eq!("", config["dev"]["s3"]);
+        assert_eq!("us-east-1", config["spaced"]["region"]);
+    }
+
+    fn error(result: Result<AwsCredentialsProvider>) -> String {
+        match result {
+            Ok(_) => panic!("the profile should not be resolved"),
+            Err(e) => e.message,
+        }
+    }
+
+    #[test]
+    fn resolves_sso_profiles() {
+        let config = parse_config(
+            "[profile app]
+sso_session = corp
+sso_account_id = 123456789012
+sso_role_name = ReadOnly
+[sso-session corp]
+sso_region = eu-west-1
+sso_start_url = https://corp.awsapps.com/start
+[profile legacy]
+sso_account_id = 123456789012
+sso_role_name = Admin
+sso_region = us-east-1
+sso_start_url = https://legacy.awsapps.com/start
+[profile broken]
+sso_account_id = 123456789012
+sso_region = us-east-1
+sso_start_url = https://legacy.awsapps.com/start
+",
+        );
+        let region = Region::EuCentral1;
+
+        let provider = AwsCredentialsProvider::from_config(&config, &region, "app", 0).unwrap();
+        let AwsCredentialsProvider::Sso(provider) = provider else {
+            panic!("should use AWS SSO");
+        };
+        let sso = provider.get_ref();
+        assert_eq!("https://corp.awsapps.com/start", sso.start_url);
+        assert_eq!("123456789012", sso.account_id);
+        assert_eq!("ReadOnly", sso.role_name);
+
+        let provider = AwsCredentialsProvider::from_config(&config, &region, "legacy", 0).unwrap();
+        let AwsCredentialsProvider::Sso(provider) = provider else {
+            panic!("should use AWS SSO");
+        };
+        assert_eq!("Admin", provider.get_ref().role_name);
+
+        assert_eq!(
+            "the profile 'broken' does not set sso_role_name",
+            error(AwsCredentialsProvider::from_config(
+                &config, &region, "broken", 0
+            ))
+        );
+    }
+
+    #[test]
+    fn follows_source_profiles() {
+        let config = parse_config(
+            "[profile app]
+role_arn = arn:aws:iam::123456789012:role/app
+source_profile = base
+[profile base]
+role_arn = arn:aws:iam::123456789012:role/base
+source_profile = base
+[profile broken]
+role_arn = arn:aws:iam::123456789012:role/app
+source_profile = sso
+[profile sso]
+sso_account_id = 123456789012
+",
+        );
+        let region = Region::EuCentral1;
+
+        assert!(matches!(
+            AwsCredentialsProvider::from_config(&config, &region, "app", 0),
+            Ok(AwsCredentialsProvider::AssumeRole(_))
+        ));
+        assert!(matches!(
+            AwsCredentialsProvider::from_config(&config, &region, "base", 0),
+            Ok(AwsCredentialsProvider::AssumeRole(_))
+        ));
+        // The error comes from the source profile, so the chain is followed.
+        assert_eq!(
+            "the profile 'sso' does not set sso_region",
+            error(AwsCredentialsProvider::from_config(
+                &config, &region, "broken", 0
+            ))
+        );
+    }
+
+    #[test]
+    fn limits_source_profile_chains() {
+        // `p0` assumes a role with `p1`, which assumes a role with `p2` and so on.
+        let chain = |len: usize| {
+            let profiles = (0..len).map(|i| {
+                format!("[profile p{i}]\nrole_arn = arn:aws:iam::123456789012:role/r{i}\nsource_profile = p{}\n", i + 1)
+            });
+            parse_config(&profiles.collect::<String>())
+        };
+        let region = Region::EuCentral1;
+
+        assert!(matches!(
+            AwsCredentialsProvider::from_config(&chain(5), &region, "p0", 0),
+            Ok(AwsCredentialsProvider::AssumeRole(_))
+        ));
+        assert_eq!(
+            "too many chained `source_profile`s in the profile 'p5'",
+            error(AwsCredentialsProvider::from_config(
+                &chain(6),
+                &region,
+                "p0",
+                0
+            ))
+        );
+
+        let cycle = parse_config(
+            "[profile a]\nrole_arn = arn:aws:iam::1:role/a\nsource_profile = b\n\
+            [profile b]\nrole_arn = arn:aws:iam::1:role/b\nsource_profile = a\n",
+        );
+        assert!(
+            error(AwsCredentialsProvider::from_config(&cycle, &region, "a", 0))
+                .starts_with("too many chained")
+        );
+    }
+
+    #[test]
+    fn checks_cached_sso_tokens() {
+        let now = Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap();
+        let token = |url: &str, expires_at: &str| CachedToken {
+            start_url: Some(url.to_string()),
+            access_token: Some("token".to_string()),
+            expires_at: Some(expires_at.to_string()),
+        };
+        assert!(token("https://corp", "2023-01-01T13:00:00Z").is_valid_for("https://corp", now));
+        assert!(token("https://corp", "2023-01-01T13:00:00UTC").is_valid_for("https://corp", now));
+        assert!(!token("https://corp", "2023-01-01T11:00:00Z").is_valid_for("https://corp", now));
+        assert!(!token("https://other", "2023-01-01T13:00:00Z").is_valid_for("https://corp", now));
+    }
+}
@@ -5,7 +5,7 @@ use azure_identity::{
     ClientSecretCredential, DefaultAzureCredentialBuilder, TokenCredentialOptions,
 };
 use azure_security_keyvault::prelude::*;
-use clap::{arg, command, ArgGroup, Args};
+use clap::{ArgGroup, Args};
 use futures::future::try_join_all;
 use futures::stream::StreamExt;
 use serde_json::Value;
@@ -153,6 +153,14 @@ impl VaultConfig for AzureConfig {
         self.enabled
     }
 
+    fn origin_args(&self) -> Vec<String> {
+        let mut args = vec!["--azure".to_string()];
+        if let Ok(address) = self.get_kv_address() {
+            args.extend(["--azure-keyvault-url".to_string(), address]);
+        }
+        args
+    }
+
     fn into_vault(self) -> anyhow::Result<Self::Vault> {
         let kv_address = self.get_kv_address()?;
         let credential = self.credential.to_credential()?;
@@ -1,5 +1,5 @@
 use base64::{engine::general_purpose::STANDARD as base64, Engine as _};
-use clap::{arg, ArgGroup, Args};
+use clap::{ArgGroup, Args};
 use google_secretmanager1::{
     hyper, hyper::client::HttpConnector, hyper_rustls, hyper_rustls::HttpsConnector, oauth2,
 };
@@ -76,6 +76,20 @@ impl VaultConfig for GoogleConfig {
         self.enabled
     }
 
+    fn origin_args(&self) -> Vec<String> {
+        let mut args = vec!["--google".to_string()];
+        if let Some(project) = &self.google_project {
+            args.extend(["--google-project".to_string(), project.clone()]);
+        }
+        if let Some(path) = &self.google_credentials_file {
+            args.extend([
+                "--google-credentials-file".to_string(),
+                path.display().to_string(),
+            ]);
+        }
+        args
+    }
+
     fn into_vault(self) -> anyhow::Result<Self::Vault> {
         Ok(self)
     }
@@ -0,0 +1,209 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+/// A lease of short-lived credentials (e.g. from a dynamic secrets engine of Hashicorp Vault).
+pub trait Lease: Send {
+    fn id(&self) -> &str;
+    /// The duration of the lease, or `None` if it cannot be renewed.
+    fn renewable_for(&self) -> Option<Duration>;
+    /// Renews the lease and returns its new duration - zero if it cannot be renewed anymore.
+    fn renew(&self) -> Result<Duration>;
+    fn revoke(&self) -> Result<()>;
+}
+
+/// The leases are renewed when two thirds of their duration pass, like the Vault agent does.
+fn renew_after(duration: Duration) -> Duration {
+    duration * 2 / 3
+}
+
+/// How long to wait before renewing the lease again if the renewal fails.
+fn retry_after(duration: Duration) -> Duration {
+    (duration / 10).max(Duration::from_secs(1))
+}
+
+struct Tracked {
+    lease: Box<dyn Lease>,
+    duration: Duration,
+    renew_at: Option<Instant>,
+}
+
+impl Tracked {
+    fn new(lease: Box<dyn Lease>) -> Self {
+        let duration = lease.renewable_for().unwrap_or_default();
+        let renew_at = lease
+            .renewable_for()
+            .map(|d| Instant::now() + renew_after(d));
+        Self {
+            lease,
+            duration,
+            renew_at,
+        }
+    }
+
+    fn renew(&mut self) {
+        match self.lease.renew() {
+            Ok(duration) if duration.is_zero() => self.renew_at = None,
+            Ok(duration) => self.renew_at = Some(Instant::now() + renew_after(duration)),
+            Err(e) => {
+                eprintln!("kvenv: cannot renew lease '{}': {:#}", self.lease.id(), e);
+                self.renew_at = Some(Instant::now() + retry_after(self.duration));
+            }
+        }
+    }
+}
+
+/// Renews the leases in the background while the command runs, and revokes them once it exits.
+pub struct LeaseKeeper {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<thread::JoinHandle<Vec<Tracked>>>,
+}
+
+/// Renews the leases until the keeper is stopped. The leases are owned by the renewal thread, so
+/// the (blocking) renewals never hold the lock, and returned once it stops.
+fn keep_renewing(mut leases: Vec<Tracked>, stopped: &(Mutex<bool>, Condvar)) -> Vec<Tracked> {
+    let (lock, cvar) = stopped;
+    loop {
+        let now = Instant::now();
+        for tracked in &mut leases {
+            if tracked.renew_at.is_some_and(|at| at <= now) {
+                tracked.renew();
+            }
+        }
+
+        let next = leases.iter().filter_map(|t| t.renew_at).min();
+        let guard = lock.lock().unwrap();
+        let guard = match next {
+            Some(at) => {
+                let timeout = at.saturating_duration_since(Instant::now());
+                cvar.wait_timeout_while(guard, timeout, |stopped| !*stopped)
+                    .unwrap()
+                    .0
+            }
+            None => cvar.wait_while(guard, |stopped| !*stopped).unwrap(),
+        };
+        if *guard {
+            return leases;
+        }
+    }
+}
+
+impl LeaseKeeper {
+    pub fn start(leases: Vec<Box<dyn Lease>>) -> Self {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        if leases.is_empty() {
+            return Self {
+                stopped,
+                thread: None,
+            };
+        }
+
+        let leases = leases.into_iter().map(Tracked::new).collect();
+        let thread = {
+            let stopped = stopped.clone();
+            thread::spawn(move || keep_renewing(leases, &stopped))
+        };
+        Self {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.thread.is_none()
+    }
+
+    /// Stops renewing the leases and revokes them.
+    pub fn stop(self) {
+        let Some(thread) = self.thread else {
+            return;
+        };
+        let (lock, cvar) = &*self.stopped;
+        *lock.lock().unwrap() = true;
+        cvar.notify_one();
+
+        let leases = thread.join().expect("lease renewal should not panic");
+        for tracked in leases {
+            if let Err(e) = tracked.lease.revoke() {
+                eprintln!(
+                    "kvenv: cannot revoke lease '{}': {:#}",
+                    tracked.lease.id(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Counters {
+        renewed: AtomicUsize,
+        revoked: AtomicUsize,
+    }
+
+    struct TestLease {
+        duration: Option<Duration>,
+        counters: Arc<Counters>,
+    }
+
+    impl Lease for TestLease {
+        fn id(&self) -> &str {
+            "test"
+        }
+
+        fn renewable_for(&self) -> Option<Duration> {
+            self.duration
+        }
+
+        fn renew(&self) -> Result<Duration> {
+            self.counters.renewed.fetch_add(1, Ordering::SeqCst);
+            Ok(self.duration.unwrap())
+        }
+
+        fn revoke(&self) -> Result<()> {
+            self.counters.revoked.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn lease(duration: Option<Duration>, counters: &Arc<Counters>) -> Box<dyn Lease> {
+        Box::new(TestLease {
+            duration,
+            counters: counters.clone(),
+        })
+    }
+
+    #[test]
+    fn renews_and_revokes_leases() {
+        let renewable = Arc::new(Counters::default());
+        let fixed = Arc::new(Counters::default());
+        let keeper = LeaseKeeper::start(vec![
+            lease(Some(Duration::from_millis(150)), &renewable),
+            lease(None, &fixed),
+        ]);
+
+        thread::sleep(Duration::from_millis(500));
+        keeper.stop();
+
+        assert!(renewable.renewed.load(Ordering::SeqCst) >= 2);
+        assert_eq!(0, fixed.renewed.load(Ordering::SeqCst));
+        assert_eq!(1, renewable.revoked.load(Ordering::SeqCst));
+        assert_eq!(1, fixed.revoked.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn stops_without_leases() {
+        let keeper = LeaseKeeper::start(vec![]);
+        assert!(keeper.is_empty());
+        keeper.stop();
+    }
+}
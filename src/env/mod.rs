@@ -1,17 +1,27 @@
-use anyhow::Result;
-use clap::{arg, ArgGroup, Args};
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use anyhow::{bail, Result};
+use clap::{ArgGroup, Args, FromArgMatches};
 
 #[cfg(feature = "aws")]
+#[allow(clippy::result_large_err)]
 mod aws;
 #[cfg(feature = "azure")]
 mod azure;
 #[cfg(feature = "google")]
+#[allow(clippy::result_large_err)]
 mod google;
+#[cfg(feature = "aws")]
+#[allow(clippy::result_large_err)]
+mod ssm;
 #[cfg(feature = "vault")]
 mod vault;
 
 mod convert;
+mod lease;
+mod pattern;
 mod process_env;
+mod source;
 
 #[cfg(feature = "aws")]
 use aws::AwsConfig;
@@ -19,19 +29,42 @@ use aws::AwsConfig;
 use azure::AzureConfig;
 #[cfg(feature = "google")]
 use google::GoogleConfig;
+#[cfg(feature = "aws")]
+use ssm::{SsmConfig, SsmVault};
 #[cfg(feature = "vault")]
 use vault::HashicorpVaultConfig;
 
-pub use process_env::ProcessEnv;
+pub use lease::{Lease, LeaseKeeper};
+use pattern::parse_pattern;
+pub use process_env::{EnvFileError, ProcessEnv, SecretVersion};
+pub use source::{Backend, ConflictPolicy, FieldMap, Selector, Source, SourceSpec};
 
 pub trait Vault {
     fn download_prefixed(&self, prefix: &str) -> Result<Vec<(String, String)>>;
     fn download_json(&self, secret_name: &str) -> Result<Vec<(String, String)>>;
+
+    /// Reads an arbitrary path, mapping the fields of the response to variables.
+    fn download_path(&self, _path: &str, _map: &[FieldMap]) -> Result<Vec<(String, String)>> {
+        bail!("reading arbitrary paths is supported only by Hashicorp Vault")
+    }
+
+    /// Takes the leases of the credentials downloaded so far, so they can be renewed and revoked.
+    fn take_leases(&self) -> Vec<Box<dyn Lease>> {
+        Vec::new()
+    }
+
+    /// Takes the versions of the secrets downloaded so far, if the backend reports them.
+    fn take_versions(&self) -> Vec<SecretVersion> {
+        Vec::new()
+    }
 }
 
 pub trait VaultConfig {
     type Vault: Vault;
     fn is_enabled(&self) -> bool;
+    /// Non-secret command-line arguments that recreate this configuration. Credentials are not
+    /// included - they need to be provided by the environment when the configuration is recreated.
+    fn origin_args(&self) -> Vec<String>;
     fn into_vault(self) -> Result<Self::Vault>;
 }
 
@@ -58,18 +91,57 @@ pub struct DataConfig {
     )]
     secret_prefix: Option<String>,
 
-    /// Environment variables that should be masked by the subsequent calls to `with`.
-    #[arg(short, long, display_order = 3)]
+    /// A source of the environment, in the `[BACKEND:]name=SECRET` or `[BACKEND:]prefix=PREFIX`
+    /// form, optionally followed by `,on-conflict=override|keep-first|fail`. Can be specified
+    /// multiple times - sources are downloaded in order and later ones take precedence. Cannot be
+    /// used along `secret-name` nor `secret-prefix`.
+    #[arg(long, value_name = "SOURCE", group = "secret", display_order = 3)]
+    source: Vec<SourceSpec>,
+
+    /// What to do when a source defines a variable that is already defined by one of the previous
+    /// sources. Can be overridden per source.
+    #[arg(long, value_enum, default_value_t, display_order = 4)]
+    on_conflict: ConflictPolicy,
+
+    /// Environment variables that should be masked by the subsequent calls to `with`. Supports
+    /// glob patterns (e.g. `AWS_*`) and regular expressions prefixed with `re:` (e.g.
+    /// `re:^(AWS|AZURE)_`).
+    #[arg(short, long, value_parser = parse_pattern, display_order = 5)]
     mask: Vec<String>,
+
+    /// Mask all the environment variables the options of the enabled backends are read from (e.g.
+    /// `AWS_SECRET_ACCESS_KEY` or `VAULT_TOKEN`), so the command does not see kvenv's credentials.
+    #[arg(long, display_order = 6)]
+    mask_backend_credentials: bool,
+
+    /// Start the command's environment from scratch instead of the OS environment. Only the
+    /// downloaded variables and the ones allowed by `keep` are passed to the command.
+    #[arg(long, display_order = 7)]
+    clean_env: bool,
+
+    /// The OS environment variable that should be passed to the command in the `clean-env` mode.
+    /// Supports the same patterns as `mask`. Can be specified multiple times.
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        value_parser = parse_pattern,
+        requires = "clean_env",
+        display_order = 8
+    )]
+    keep: Vec<String>,
 }
 
 #[derive(Args, Debug)]
-#[command(group = ArgGroup::new("cloud").required(true).multiple(false))]
+#[command(group = ArgGroup::new("cloud").required(true).multiple(true))]
 pub struct EnvConfig {
     #[cfg(feature = "aws")]
     #[command(flatten)]
     aws: AwsConfig,
 
+    #[cfg(feature = "aws")]
+    #[command(flatten)]
+    aws_ssm: SsmConfig,
+
     #[cfg(feature = "azure")]
     #[command(flatten)]
     azure: AzureConfig,
@@ -86,26 +158,121 @@ pub struct EnvConfig {
     data: DataConfig,
 }
 
+type Vaults = Vec<(Backend, Rc<dyn Vault>)>;
+
+/// Names of the environment variables the arguments of `T` are read from.
+fn arg_env_vars<T: Args>() -> Vec<String> {
+    T::augment_args(clap::Command::new("kvenv"))
+        .get_arguments()
+        .filter_map(|a| a.get_env())
+        .map(|e| e.to_string_lossy().into_owned())
+        .collect()
+}
+
 impl EnvConfig {
-    fn into_run_config(self) -> Result<(Box<dyn Vault>, DataConfig)> {
+    /// Recreates the configuration from the arguments returned by `ProcessEnv::origin_args`.
+    pub fn from_origin(args: &[String]) -> Result<Self> {
+        let cmd = Self::augment_args(clap::Command::new("kvenv").no_binary_name(true));
+        let matches = cmd.try_get_matches_from(args)?;
+        Ok(Self::from_arg_matches(&matches)?)
+    }
+
+    fn backend_origin_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+
+        #[cfg(feature = "aws")]
+        if self.aws.is_enabled() {
+            args.extend(self.aws.origin_args());
+        }
+
+        #[cfg(feature = "aws")]
+        if self.aws_ssm.is_enabled() {
+            args.extend(self.aws_ssm.origin_args());
+        }
+
+        #[cfg(feature = "aws")]
+        if self.aws.is_enabled() || self.aws_ssm.is_enabled() {
+            args.extend(self.aws.credentials.origin_args());
+        }
+
+        #[cfg(feature = "azure")]
+        if self.azure.is_enabled() {
+            args.extend(self.azure.origin_args());
+        }
+
+        #[cfg(feature = "google")]
+        if self.google.is_enabled() {
+            args.extend(self.google.origin_args());
+        }
+
+        #[cfg(feature = "vault")]
+        if self.vault.is_enabled() {
+            args.extend(self.vault.origin_args());
+        }
+
+        args
+    }
+
+    /// Names of the environment variables the options of the enabled backends are read from.
+    fn backend_env_vars(&self) -> Vec<String> {
+        let mut vars = Vec::new();
+
+        #[cfg(feature = "aws")]
+        if self.aws.is_enabled() || self.aws_ssm.is_enabled() {
+            vars.extend(arg_env_vars::<AwsConfig>());
+        }
+
+        #[cfg(feature = "aws")]
+        if self.aws_ssm.is_enabled() {
+            vars.extend(arg_env_vars::<SsmConfig>());
+        }
+
+        #[cfg(feature = "azure")]
+        if self.azure.is_enabled() {
+            vars.extend(arg_env_vars::<AzureConfig>());
+        }
+
+        #[cfg(feature = "google")]
+        if self.google.is_enabled() {
+            vars.extend(arg_env_vars::<GoogleConfig>());
+        }
+
+        #[cfg(feature = "vault")]
+        if self.vault.is_enabled() {
+            vars.extend(arg_env_vars::<HashicorpVaultConfig>());
+        }
+
+        vars
+    }
+
+    fn into_vaults(self) -> Result<(Vaults, DataConfig)> {
+        let mut vaults: Vaults = Vec::new();
+
+        #[cfg(feature = "aws")]
+        if self.aws_ssm.is_enabled() {
+            let credentials = self.aws.credentials.clone();
+            let connection = credentials.connect(self.aws_ssm.endpoint_url())?;
+            vaults.push((Backend::AwsSsm, Rc::new(SsmVault::new(connection))));
+        }
+
         #[cfg(feature = "aws")]
         if self.aws.is_enabled() {
-            return Ok((Box::new(self.aws.into_vault()?), self.data));
+            vaults.push((Backend::Aws, Rc::new(self.aws.into_vault()?)));
         }
 
         #[cfg(feature = "azure")]
         if self.azure.is_enabled() {
-            return Ok((Box::new(self.azure.into_vault()?), self.data));
+            vaults.push((Backend::Azure, Rc::new(self.azure.into_vault()?)));
         }
 
         #[cfg(feature = "google")]
         if self.google.is_enabled() {
-            return Ok((Box::new(self.google.into_vault()?), self.data));
+            vaults.push((Backend::Google, Rc::new(self.google.into_vault()?)));
         }
 
         #[cfg(feature = "vault")]
         if self.vault.is_enabled() {
-            return Ok((Box::new(self.vault.into_vault()?), self.data));
+            vaults.push((Backend::Vault, Rc::new(self.vault.into_vault()?)));
         }
 
         #[cfg(not(any(
@@ -116,18 +283,237 @@ impl EnvConfig {
         )))]
         compile_error!("no cloud configured");
 
-        unreachable!()
+        Ok((vaults, self.data))
+    }
+
+    fn into_sources(self) -> Result<(Vec<Source>, DataConfig)> {
+        let (vaults, data) = self.into_vaults()?;
+        let sources = resolve_sources(&vaults, data.source_specs(), data.on_conflict)?;
+        Ok((sources, data))
+    }
+}
+
+impl DataConfig {
+    fn source_specs(&self) -> Vec<SourceSpec> {
+        let single = |selector| SourceSpec {
+            backend: None,
+            selector,
+            on_conflict: None,
+        };
+
+        if let Some(name) = &self.secret_name {
+            vec![single(Selector::Name(name.clone()))]
+        } else if let Some(prefix) = &self.secret_prefix {
+            vec![single(Selector::Prefix(prefix.clone()))]
+        } else {
+            self.source.clone()
+        }
+    }
+}
+
+fn resolve_sources(
+    vaults: &Vaults,
+    specs: Vec<SourceSpec>,
+    on_conflict: ConflictPolicy,
+) -> Result<Vec<Source>> {
+    specs
+        .into_iter()
+        .map(|spec| {
+            let (backend, vault) = match spec.backend {
+                Some(backend) => match vaults.iter().find(|(b, _)| *b == backend) {
+                    Some(v) => v,
+                    None => bail!(
+                        "source '{}' uses backend '{}' that is not enabled",
+                        spec.selector,
+                        backend
+                    ),
+                },
+                None if vaults.len() == 1 => &vaults[0],
+                None => bail!(
+                    "source '{}' does not specify the backend, but multiple backends are enabled \
+                    (use `--source BACKEND:...`)",
+                    spec.selector
+                ),
+            };
+            Ok(Source {
+                backend: *backend,
+                selector: spec.selector,
+                on_conflict: spec.on_conflict.unwrap_or(on_conflict),
+                vault: vault.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Downloads the environment from the configured sources, possibly multiple times (e.g. to watch
+/// the secrets for changes).
+///
+/// The sources that return leased credentials (e.g. `path=` sources of dynamic secrets engines)
+/// are downloaded only once, and their variables are reused afterwards - downloading them again
+/// would mint new credentials every time.
+pub struct EnvDownloader {
+    backend_args: Vec<String>,
+    sources: Vec<Source>,
+    mask: Vec<String>,
+    keep: Option<Vec<String>>,
+    snapshot_env: bool,
+    leased: RefCell<HashMap<usize, Vec<(String, String)>>>,
+    leases: RefCell<Vec<Box<dyn Lease>>>,
+}
+
+impl EnvDownloader {
+    pub fn new(cfg: EnvConfig, snapshot_env: bool) -> Result<Self> {
+        let backend_args = cfg.backend_origin_args();
+        let credentials = cfg.backend_env_vars();
+        let (sources, cfg) = cfg.into_sources()?;
+
+        let mut mask = cfg.mask;
+        if cfg.mask_backend_credentials {
+            mask.extend(credentials);
+        }
+        Ok(Self {
+            backend_args,
+            sources,
+            mask,
+            keep: cfg.clean_env.then_some(cfg.keep),
+            snapshot_env,
+            leased: RefCell::default(),
+            leases: RefCell::default(),
+        })
+    }
+
+    pub fn download(&self) -> Result<ProcessEnv> {
+        let from_kv = source::download_sources(&self.sources, |i, s| self.download_source(i, s))?;
+        let specs = self.sources.iter().map(Source::to_spec).collect();
+        let versions = self
+            .sources
+            .iter()
+            .flat_map(|s| s.vault.take_versions())
+            .collect();
+        let mut env = ProcessEnv::new(from_kv, self.mask.clone(), self.snapshot_env)
+            .with_origin(self.backend_args.clone(), specs)
+            .with_versions(versions);
+        if let Some(keep) = &self.keep {
+            env = env.with_clean_env(keep.clone());
+        }
+        Ok(env)
+    }
+
+    fn download_source(&self, index: usize, source: &Source) -> Result<Vec<(String, String)>> {
+        if let Some(vars) = self.leased.borrow().get(&index) {
+            return Ok(vars.clone());
+        }
+        let vars = source.download()?;
+        let leases = source.vault.take_leases();
+        if !leases.is_empty() {
+            self.leased.borrow_mut().insert(index, vars.clone());
+            self.leases.borrow_mut().extend(leases);
+        }
+        Ok(vars)
+    }
+
+    /// Takes the leases of the credentials downloaded so far.
+    pub fn take_leases(&self) -> Vec<Box<dyn Lease>> {
+        self.leases.take()
     }
 }
 
 pub fn download_env(cfg: EnvConfig, snapshot_env: bool) -> Result<ProcessEnv> {
-    let (vault, cfg) = cfg.into_run_config()?;
-    let from_kv = if cfg.secret_name.is_some() {
-        vault.download_json(&cfg.secret_name.unwrap())?
-    } else if cfg.secret_prefix.is_some() {
-        vault.download_prefixed(&cfg.secret_prefix.unwrap())?
-    } else {
-        unreachable!()
-    };
-    Ok(ProcessEnv::new(from_kv, cfg.mask, snapshot_env))
+    EnvDownloader::new(cfg, snapshot_env)?.download()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, time::Duration};
+
+    use super::*;
+
+    struct DynamicVault {
+        downloads: Cell<usize>,
+        leases: RefCell<Vec<Box<dyn Lease>>>,
+    }
+
+    struct NoopLease;
+
+    impl Lease for NoopLease {
+        fn id(&self) -> &str {
+            "noop"
+        }
+        fn renewable_for(&self) -> Option<Duration> {
+            None
+        }
+        fn renew(&self) -> Result<Duration> {
+            Ok(Duration::ZERO)
+        }
+        fn revoke(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Vault for DynamicVault {
+        fn download_prefixed(&self, _: &str) -> Result<Vec<(String, String)>> {
+            self.downloads.set(self.downloads.get() + 1);
+            Ok(vec![("A".to_string(), self.downloads.get().to_string())])
+        }
+        fn download_json(&self, _: &str) -> Result<Vec<(String, String)>> {
+            Ok(vec![])
+        }
+        fn download_path(&self, _: &str, _: &[FieldMap]) -> Result<Vec<(String, String)>> {
+            self.downloads.set(self.downloads.get() + 1);
+            self.leases.borrow_mut().push(Box::new(NoopLease));
+            Ok(vec![(
+                "DB_USER".to_string(),
+                self.downloads.get().to_string(),
+            )])
+        }
+        fn take_leases(&self) -> Vec<Box<dyn Lease>> {
+            self.leases.take()
+        }
+    }
+
+    #[test]
+    fn downloads_leased_sources_once() {
+        let vault = Rc::new(DynamicVault {
+            downloads: Cell::new(0),
+            leases: RefCell::default(),
+        });
+        let source = |selector| Source {
+            backend: Backend::Vault,
+            selector,
+            on_conflict: ConflictPolicy::Override,
+            vault: vault.clone(),
+        };
+        let downloader = EnvDownloader {
+            backend_args: vec![],
+            sources: vec![
+                source(Selector::Path(source::PathSelector {
+                    path: "database/creds/app".to_string(),
+                    map: vec![],
+                })),
+                source(Selector::Prefix("app/".to_string())),
+            ],
+            mask: vec![],
+            keep: None,
+            snapshot_env: false,
+            leased: RefCell::default(),
+            leases: RefCell::default(),
+        };
+
+        let first = downloader.download().unwrap();
+        assert_eq!(1, downloader.take_leases().len());
+        let second = downloader.download().unwrap();
+        assert!(downloader.take_leases().is_empty());
+
+        assert_eq!(vec!["A"], first.changed_keys(&second));
+        assert_eq!(3, vault.downloads.get());
+    }
+
+    #[cfg(feature = "vault")]
+    #[test]
+    fn lists_backend_env_vars() {
+        let vars = arg_env_vars::<HashicorpVaultConfig>();
+        assert!(vars.contains(&"VAULT_TOKEN".to_string()));
+        assert!(vars.contains(&"VAULT_ADDR".to_string()));
+        assert!(!vars.iter().any(|v| v.starts_with("AWS_")));
+    }
 }
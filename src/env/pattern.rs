@@ -0,0 +1,112 @@
+use regex::Regex;
+
+/// Patterns with this prefix are regular expressions, the rest are globs.
+const REGEX_PREFIX: &str = "re:";
+
+/// Matches the variable name against the glob pattern, where `*` matches any (possibly empty)
+/// sequence of characters and `?` matches exactly one character.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // The position of the last `*` in the pattern and of the name when it was reached.
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` match one more character.
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// A compiled set of variable name patterns - globs, or regular expressions prefixed with `re:`.
+pub struct Patterns {
+    globs: Vec<String>,
+    regexes: Vec<Regex>,
+}
+
+impl Patterns {
+    pub fn new(patterns: &[String]) -> Result<Self, regex::Error> {
+        let mut result = Self {
+            globs: Vec::new(),
+            regexes: Vec::new(),
+        };
+        for p in patterns {
+            match p.strip_prefix(REGEX_PREFIX) {
+                Some(re) => result.regexes.push(Regex::new(re)?),
+                None => result.globs.push(p.clone()),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Whether the variable name matches any of the patterns.
+    pub fn matches(&self, name: &str) -> bool {
+        self.globs.iter().any(|g| glob_match(g, name))
+            || self.regexes.iter().any(|r| r.is_match(name))
+    }
+}
+
+/// Validates the pattern given on the command line.
+pub fn parse_pattern(s: &str) -> Result<String, String> {
+    match s.strip_prefix(REGEX_PREFIX).map(Regex::new) {
+        Some(Err(e)) => Err(e.to_string()),
+        _ => Ok(s.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("PATH", "PATH"));
+        assert!(!glob_match("PATH", "PATHS"));
+        assert!(glob_match("LC_*", "LC_ALL"));
+        assert!(glob_match("LC_*", "LC_"));
+        assert!(!glob_match("LC_*", "LANG"));
+        assert!(glob_match("*_KEY", "AWS_SECRET_ACCESS_KEY"));
+        assert!(glob_match("A*B*C", "AxxBxBxxC"));
+        assert!(!glob_match("A*B*C", "AxxBxxD"));
+        assert!(glob_match("?OME", "HOME"));
+        assert!(!glob_match("?OME", "OME"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn matches_any_pattern() {
+        let patterns = ["PATH", "LC_*", "re:^(AWS|AZURE)_"].map(String::from);
+        let patterns = Patterns::new(&patterns).unwrap();
+        assert!(patterns.matches("LC_CTYPE"));
+        assert!(patterns.matches("AZURE_CLIENT_SECRET"));
+        assert!(!patterns.matches("HOME"));
+        assert!(!patterns.matches("MY_AWS_KEY"));
+        assert!(!Patterns::new(&[]).unwrap().matches("HOME"));
+    }
+
+    #[test]
+    fn validates_patterns() {
+        assert_eq!(Ok("re:^A+$".to_string()), parse_pattern("re:^A+$"));
+        assert_eq!(Ok("A(*".to_string()), parse_pattern("A(*"));
+        assert!(parse_pattern("re:A(").is_err());
+        assert!(Patterns::new(&["re:A(".to_string()]).is_err());
+    }
+}
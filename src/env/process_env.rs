@@ -1,14 +1,41 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-#[derive(Debug, Serialize, Deserialize)]
+use super::pattern::Patterns;
+use super::SourceSpec;
+
+/// The version of the environment file format. Version 0 is the bare `ProcessEnv` that was
+/// written by kvenv 0.4 and older.
+const FORMAT_VERSION: u64 = 1;
+
+#[derive(Error, Debug)]
+pub enum EnvFileError {
+    #[error("the environment file is not valid")]
+    Invalid(#[from] serde_json::Error),
+    #[error("the environment file has invalid format version")]
+    InvalidVersion,
+    #[error("the environment file has invalid variable pattern")]
+    InvalidPattern(#[from] regex::Error),
+    #[error(
+        "the environment file was created by newer kvenv ({kvenv_version}) and uses format \
+        version {version}, but only versions up to {FORMAT_VERSION} are supported"
+    )]
+    UnsupportedVersion { version: u64, kvenv_version: String },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 enum OsEnv {
     Persisted(Vec<(String, String)>),
     Fresh(Vec<(String, String)>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ProcessEnv {
     #[serde(
         skip_serializing_if = "OsEnv::should_not_persist",
@@ -17,6 +44,75 @@ pub struct ProcessEnv {
     from_env: OsEnv,
     from_kv: Vec<(String, String)>,
     masked: Vec<String>,
+    /// If set, only the OS environment variables matching these patterns are passed to the
+    /// command (along with the downloaded ones).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keep: Option<Vec<String>>,
+    #[serde(skip)]
+    metadata: Metadata,
+}
+
+/// Describes where the environment was downloaded from and when.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Metadata {
+    /// When the environment was downloaded, in seconds since the UNIX epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fetched_at: Option<u64>,
+    /// When the environment expires, in seconds since the UNIX epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    /// The sources the environment was downloaded from, in order.
+    #[serde(default)]
+    sources: Vec<SourceSpec>,
+    /// Non-secret arguments that configure the backends used by `sources`.
+    #[serde(default)]
+    backend_args: Vec<String>,
+    /// The versions of the secrets the environment was downloaded from, if the backend reports
+    /// them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    versions: Vec<SecretVersion>,
+}
+
+/// The version of a secret the environment was downloaded from.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SecretVersion {
+    pub secret: String,
+    pub version_id: String,
+    /// The staging labels attached to the version (e.g. `AWSCURRENT`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stages: Vec<String>,
+}
+
+impl fmt::Display for SecretVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' version {}", self.secret, self.version_id)?;
+        if !self.stages.is_empty() {
+            write!(f, " ({})", self.stages.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct EnvelopeRef<'a> {
+    version: u64,
+    kvenv_version: &'a str,
+    #[serde(flatten)]
+    metadata: &'a Metadata,
+    env: &'a ProcessEnv,
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(flatten)]
+    metadata: Metadata,
+    env: ProcessEnv,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
 }
 
 impl OsEnv {
@@ -56,23 +152,181 @@ impl ProcessEnv {
             from_env: OsEnv::new(snapshot_env),
             from_kv,
             masked,
+            keep: None,
+            metadata: Metadata {
+                fetched_at: Some(unix_now()),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Starts the command's environment from scratch, passing only the OS environment variables
+    /// matching the `keep` patterns (along with the downloaded ones).
+    pub fn with_clean_env(mut self, keep: Vec<String>) -> Self {
+        self.keep = Some(keep);
+        self
+    }
+
+    /// Records where the environment was downloaded from.
+    pub fn with_origin(mut self, backend_args: Vec<String>, sources: Vec<SourceSpec>) -> Self {
+        self.metadata.backend_args = backend_args;
+        self.metadata.sources = sources;
+        self
+    }
+
+    /// Records the versions of the secrets the environment was downloaded from.
+    pub fn with_versions(mut self, versions: Vec<SecretVersion>) -> Self {
+        self.metadata.versions = versions;
+        self
+    }
+
+    pub fn versions(&self) -> &[SecretVersion] {
+        &self.metadata.versions
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        let fetched_at = *self.metadata.fetched_at.get_or_insert_with(unix_now);
+        self.metadata.expires_at = Some(fetched_at.saturating_add(ttl.as_secs()));
+        self
+    }
+
+    /// The command-line arguments that allow downloading the environment again, if the sources
+    /// are known.
+    pub fn origin_args(&self) -> Option<Vec<String>> {
+        if self.metadata.sources.is_empty() {
+            return None;
+        }
+        let mut args = self.metadata.backend_args.clone();
+        for s in &self.metadata.sources {
+            args.extend(["--source".to_string(), s.to_string()]);
         }
+        Some(args)
     }
 
-    pub fn from_reader<R: std::io::Read>(rdr: R) -> serde_json::Result<Self> {
-        serde_json::from_reader(rdr)
+    /// Returns the expiry time, if the environment has already expired.
+    pub fn expired_at(&self) -> Option<SystemTime> {
+        self.metadata
+            .expires_at
+            .filter(|e| *e <= unix_now())
+            .map(|e| UNIX_EPOCH + Duration::from_secs(e))
+    }
+
+    /// Replaces the downloaded variables with the ones from the `fresh` environment, keeping the
+    /// OS environment (if persisted) and masks.
+    pub fn refresh(mut self, fresh: ProcessEnv) -> Self {
+        self.from_kv = fresh.from_kv;
+        self.metadata.fetched_at = fresh.metadata.fetched_at;
+        self.metadata.versions = fresh.metadata.versions;
+        self.metadata.expires_at = None;
+        self
+    }
+
+    /// Reads the environment file, migrating it from older format versions if needed.
+    pub fn from_reader<R: std::io::Read>(rdr: R) -> Result<Self, EnvFileError> {
+        let value: serde_json::Value = serde_json::from_reader(rdr)?;
+        let version = match value.get("version") {
+            Some(v) => v.as_u64().ok_or(EnvFileError::InvalidVersion)?,
+            None => 0,
+        };
+
+        let env: Self = match version {
+            0 => serde_json::from_value(value)?,
+            FORMAT_VERSION => {
+                let envelope: Envelope = serde_json::from_value(value)?;
+                Self {
+                    metadata: envelope.metadata,
+                    ..envelope.env
+                }
+            }
+            version => {
+                let kvenv_version = value
+                    .get("kvenv_version")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("unknown version")
+                    .to_string();
+                return Err(EnvFileError::UnsupportedVersion {
+                    version,
+                    kvenv_version,
+                });
+            }
+        };
+
+        // The patterns are used without further checks later on.
+        Patterns::new(&env.masked)?;
+        Patterns::new(env.keep.as_deref().unwrap_or_default())?;
+        Ok(env)
+    }
+
+    fn masked_patterns(&self) -> Patterns {
+        Patterns::new(&self.masked).expect("mask patterns should be validated")
     }
 
     pub fn to_writer<W: std::io::Write>(&self, w: W) -> serde_json::Result<()> {
-        serde_json::to_writer(w, self)
+        let envelope = EnvelopeRef {
+            version: FORMAT_VERSION,
+            kvenv_version: env!("CARGO_PKG_VERSION"),
+            metadata: &self.metadata,
+            env: self,
+        };
+        serde_json::to_writer(w, &envelope)
+    }
+
+    /// Returns only the variables that were downloaded from the secret storage (without masked
+    /// ones), in the order they were downloaded.
+    pub fn into_downloaded(self) -> Vec<(String, String)> {
+        let masked = self.masked_patterns();
+        self.from_kv
+            .into_iter()
+            .filter(|(k, _)| !masked.matches(k))
+            .collect()
+    }
+
+    /// The value of the downloaded variable, unless it is masked.
+    pub fn get_downloaded(&self, key: &str) -> Option<&str> {
+        if self.masked_patterns().matches(key) {
+            return None;
+        }
+        self.from_kv
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Values of all the variables downloaded from the secret storage (including masked ones).
+    pub fn secret_values(&self) -> impl Iterator<Item = &str> {
+        self.from_kv.iter().map(|(_, v)| v.as_str())
+    }
+
+    /// Names of the downloaded variables that were added, removed or changed in `other`, sorted.
+    pub fn changed_keys(&self, other: &ProcessEnv) -> Vec<String> {
+        let downloaded = |e: &ProcessEnv| -> BTreeMap<String, String> {
+            e.clone().into_downloaded().into_iter().collect()
+        };
+        let (old, new) = (downloaded(self), downloaded(other));
+        let mut keys: Vec<_> = old
+            .keys()
+            .chain(new.keys())
+            .filter(|k| old.get(*k) != new.get(*k))
+            .cloned()
+            .collect();
+        keys.sort();
+        keys.dedup();
+        keys
     }
 
     pub fn into_env(self) -> HashMap<String, String> {
-        let mut map: HashMap<_, _> = self.from_env.into_iter().collect();
+        let masked = self.masked_patterns();
+        let keep = self
+            .keep
+            .map(|k| Patterns::new(&k).expect("keep patterns should be validated"));
+        let mut map: HashMap<_, _> = self
+            .from_env
+            .into_iter()
+            .filter(|(k, _)| keep.as_ref().is_none_or(|keep| keep.matches(k)))
+            .collect();
         map.extend(self.from_kv);
-        for m in self.masked {
-            map.remove(&m);
-        }
+        map.retain(|k, _| !masked.matches(k));
         map
     }
 }
@@ -93,6 +347,8 @@ mod tests {
                 from_env: OsEnv::Fresh(from_env),
                 from_kv,
                 masked,
+                keep: None,
+                metadata: Metadata::default(),
             }
         }
 
@@ -127,6 +383,8 @@ mod tests {
                 env!("E", "KV"),
             ],
             masked: vec![env!("B"), env!("E")],
+            keep: None,
+            metadata: Metadata::default(),
         };
 
         let env = env.into_env();
@@ -138,12 +396,102 @@ mod tests {
         assert_eq!(None, env.get("E"));
     }
 
+    #[test]
+    fn into_env_with_patterns() {
+        let env = ProcessEnv {
+            from_env: OsEnv::Persisted(vec![
+                env!("PATH", "ENV"),
+                env!("HOME", "ENV"),
+                env!("LC_ALL", "ENV"),
+                env!("LC_CTYPE", "ENV"),
+                env!("AWS_REGION", "ENV"),
+            ]),
+            from_kv: vec![
+                env!("DB_URL", "KV"),
+                env!("DB_USER", "KV"),
+                env!("KEY", "KV"),
+            ],
+            masked: vec![env!("DB_US*"), env!("re:^LC_.TYPE$")],
+            keep: Some(vec![env!("PATH"), env!("LC_*")]),
+            metadata: Metadata::default(),
+        };
+
+        let mut env: Vec<_> = env.into_env().into_iter().collect();
+        env.sort();
+        assert_eq!(
+            vec![
+                env!("DB_URL", "KV"),
+                env!("KEY", "KV"),
+                env!("LC_ALL", "ENV"),
+                env!("PATH", "ENV")
+            ],
+            env
+        );
+    }
+
+    #[test]
+    fn clean_env_round_trip() {
+        let env = ProcessEnv::new(vec![], vec![], false).with_clean_env(vec![env!("HOME")]);
+        assert_eq!(Some(vec![env!("HOME")]), round_trip(&env).keep);
+
+        let env = ProcessEnv::new(vec![], vec![], false);
+        assert_eq!(None, round_trip(&env).keep);
+    }
+
+    #[test]
+    fn into_downloaded() {
+        let env = ProcessEnv {
+            from_env: OsEnv::Persisted(vec![env!("A", "ENV")]),
+            from_kv: vec![env!("B", "KV"), env!("C", "KV"), env!("D", "KV")],
+            masked: vec![env!("C")],
+            keep: None,
+            metadata: Metadata::default(),
+        };
+
+        assert_eq!(
+            vec![env!("B", "KV"), env!("D", "KV")],
+            env.into_downloaded()
+        );
+    }
+
+    #[test]
+    fn changed_keys() {
+        let old = ProcessEnv::fresh(
+            vec![env!("A", "ENV")],
+            vec![
+                env!("B", "1"),
+                env!("C", "1"),
+                env!("D", "1"),
+                env!("M", "1"),
+            ],
+            vec![env!("M")],
+        );
+        let new = ProcessEnv::fresh(
+            vec![env!("A", "OTHER")],
+            vec![
+                env!("E", "1"),
+                env!("C", "2"),
+                env!("B", "1"),
+                env!("M", "2"),
+            ],
+            vec![env!("M")],
+        );
+
+        assert_eq!(
+            vec![env!("C"), env!("D"), env!("E")],
+            old.changed_keys(&new)
+        );
+        assert!(old.changed_keys(&old).is_empty());
+    }
+
     #[test]
     fn serialization_persisted() {
         let persisted = |env, kv, masked| ProcessEnv {
             from_env: OsEnv::Persisted(env),
             from_kv: kv,
             masked,
+            keep: None,
+            metadata: Metadata::default(),
         };
 
         let test = |env: &ProcessEnv| {
@@ -170,6 +518,8 @@ mod tests {
             from_env: OsEnv::Fresh(vec![env!("Ignore", "me")]),
             from_kv: kv,
             masked,
+            keep: None,
+            metadata: Metadata::default(),
         };
 
         let test = |env: &ProcessEnv| {
@@ -191,4 +541,134 @@ mod tests {
             serialized.from_env.into_iter().collect::<Vec<_>>()
         );
     }
+
+    fn round_trip(env: &ProcessEnv) -> ProcessEnv {
+        let mut buffer = Vec::new();
+        env.to_writer(&mut buffer).unwrap();
+        ProcessEnv::from_reader(&buffer[..]).unwrap()
+    }
+
+    fn source(name: &str) -> SourceSpec {
+        format!("vault:name={name},on-conflict=override")
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn expiry() {
+        let env = ProcessEnv::new(vec![], vec![], false);
+        assert!(env.metadata.fetched_at.is_some());
+        assert_eq!(None, env.expired_at());
+
+        let env = env.with_ttl(Duration::from_secs(60));
+        assert_eq!(None, env.expired_at());
+
+        let mut env = env;
+        env.metadata.fetched_at = Some(0);
+        let env = env.with_ttl(Duration::from_secs(60));
+        assert_eq!(Some(UNIX_EPOCH + Duration::from_secs(60)), env.expired_at());
+        assert_eq!(Some(60), round_trip(&env).metadata.expires_at);
+    }
+
+    #[test]
+    fn refresh_keeps_persisted_env_and_masks() {
+        let env = ProcessEnv {
+            from_env: OsEnv::Persisted(vec![env!("A", "ENV")]),
+            from_kv: vec![env!("B", "OLD")],
+            masked: vec![env!("C")],
+            keep: None,
+            metadata: Metadata {
+                fetched_at: Some(0),
+                expires_at: Some(1),
+                sources: vec![source("app")],
+                backend_args: vec![env!("--vault")],
+                versions: vec![],
+            },
+        };
+        let fresh = ProcessEnv::new(vec![env!("B", "NEW"), env!("C", "NEW")], vec![], false);
+
+        let env = env.refresh(fresh);
+        assert_eq!(None, env.expired_at());
+        assert_eq!(
+            Some(vec![
+                env!("--vault"),
+                env!("--source"),
+                env!("vault:name=app,on-conflict=override")
+            ]),
+            env.origin_args()
+        );
+
+        let env = env.into_env();
+        assert_eq!(Some(&env!("ENV")), env.get("A"));
+        assert_eq!(Some(&env!("NEW")), env.get("B"));
+        assert_eq!(None, env.get("C"));
+    }
+
+    #[test]
+    fn envelope_round_trip() {
+        let version = SecretVersion {
+            secret: env!("app"),
+            version_id: env!("v1"),
+            stages: vec![env!("AWSPREVIOUS")],
+        };
+        let env = ProcessEnv::new(vec![env!("A", "B")], vec![env!("C")], true)
+            .with_origin(vec![env!("--vault")], vec![source("app")])
+            .with_versions(vec![version.clone()])
+            .with_ttl(Duration::from_secs(60));
+
+        let mut buffer = Vec::new();
+        env.to_writer(&mut buffer).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(Some(FORMAT_VERSION), value["version"].as_u64());
+        assert_eq!(
+            Some(std::env!("CARGO_PKG_VERSION")),
+            value["kvenv_version"].as_str()
+        );
+        assert_eq!(Some("vault"), value["sources"][0]["backend"].as_str());
+        assert_eq!(Some("app"), value["sources"][0]["secret_name"].as_str());
+        assert!(value["fetched_at"].is_u64());
+
+        let deserialized = round_trip(&env);
+        assert_eq!(env.from_kv, deserialized.from_kv);
+        assert_eq!(env.masked, deserialized.masked);
+        assert!(matches!(deserialized.from_env, OsEnv::Persisted(_)));
+        assert_eq!(env.metadata.expires_at, deserialized.metadata.expires_at);
+        assert_eq!(env.origin_args(), deserialized.origin_args());
+        assert_eq!(&[version], deserialized.versions());
+        assert_eq!(
+            "'app' version v1 (AWSPREVIOUS)",
+            deserialized.versions()[0].to_string()
+        );
+    }
+
+    #[test]
+    fn migrates_unversioned_format() {
+        let env =
+            ProcessEnv::from_reader(&br#"{"from_kv":[["A","B"]],"masked":["C"]}"#[..]).unwrap();
+
+        assert_eq!(vec![env!("A", "B")], env.from_kv);
+        assert_eq!(vec![env!("C")], env.masked);
+        assert!(matches!(env.from_env, OsEnv::Fresh(_)));
+        assert_eq!(None, env.origin_args());
+        assert_eq!(None, env.expired_at());
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        let result = ProcessEnv::from_reader(&br#"{"from_kv":[],"masked":["re:A("]}"#[..]);
+        assert!(matches!(result, Err(EnvFileError::InvalidPattern(_))));
+    }
+
+    #[test]
+    fn rejects_newer_format() {
+        let result = ProcessEnv::from_reader(
+            &br#"{"version":1000,"kvenv_version":"9.0.0","something":"else"}"#[..],
+        );
+
+        assert!(matches!(
+            result,
+            Err(EnvFileError::UnsupportedVersion { version: 1000, kvenv_version })
+                if kvenv_version == "9.0.0"
+        ));
+    }
 }
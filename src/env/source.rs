@@ -0,0 +1,505 @@
+use std::{collections::HashMap, fmt, rc::Rc, str::FromStr};
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::Vault;
+
+/// The secret storage a source is downloaded from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Aws,
+    #[serde(rename = "aws-ssm")]
+    AwsSsm,
+    Azure,
+    Google,
+    Vault,
+}
+
+/// What should be downloaded from the backend.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Selector {
+    #[serde(rename = "secret_name")]
+    Name(String),
+    #[serde(rename = "secret_prefix")]
+    Prefix(String),
+    /// An arbitrary path of the secret storage (e.g. a dynamic secrets engine of Hashicorp Vault).
+    #[serde(rename = "path")]
+    Path(PathSelector),
+}
+
+/// The path to read, along with the fields of the response that become the variables.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathSelector {
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub map: Vec<FieldMap>,
+}
+
+/// Maps a field of the response (e.g. `data.username`) to a variable.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldMap {
+    pub field: String,
+    pub var: String,
+}
+
+/// What happens when a source defines a variable that was already defined by one of the
+/// previous sources.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// The later source wins.
+    #[default]
+    Override,
+    /// The earlier source wins.
+    KeepFirst,
+    /// Loading the environment fails.
+    Fail,
+}
+
+/// A single source, as specified on the command line.
+///
+/// The format is `[BACKEND:]name=SECRET`, `[BACKEND:]prefix=PREFIX` or `[BACKEND:]path=PATH`,
+/// optionally followed by `,on-conflict=POLICY`. Path sources can also be followed by any number
+/// of `,map=FIELD:VAR` options. The backend can be omitted only if there is a single backend enabled.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<Backend>,
+    #[serde(flatten)]
+    pub selector: Selector,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_conflict: Option<ConflictPolicy>,
+}
+
+/// A source that is ready to be downloaded.
+pub struct Source {
+    pub backend: Backend,
+    pub selector: Selector,
+    pub on_conflict: ConflictPolicy,
+    pub vault: Rc<dyn Vault>,
+}
+
+#[derive(Error, Debug)]
+pub enum SourceError {
+    #[error("variable '{key}' is defined by both {first} and {second}")]
+    Conflict {
+        key: String,
+        first: String,
+        second: String,
+    },
+    #[error("cannot download {0}")]
+    Download(String, #[source] anyhow::Error),
+}
+
+impl Backend {
+    const ALL: [Backend; 5] = [
+        Backend::Aws,
+        Backend::AwsSsm,
+        Backend::Azure,
+        Backend::Google,
+        Backend::Vault,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Aws => "aws",
+            Backend::AwsSsm => "aws-ssm",
+            Backend::Azure => "azure",
+            Backend::Google => "google",
+            Backend::Vault => "vault",
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match Backend::ALL.into_iter().find(|b| b.name() == s) {
+            Some(b) => Ok(b),
+            None => bail!("unknown backend '{}'", s),
+        }
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Selector::Name(n) => write!(f, "name={n}"),
+            Selector::Prefix(p) => write!(f, "prefix={p}"),
+            Selector::Path(p) => {
+                write!(f, "path={}", p.path)?;
+                for m in &p.map {
+                    write!(f, ",map={}:{}", m.field, m.var)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_possible_value().unwrap().get_name())
+    }
+}
+
+impl fmt::Display for SourceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(backend) = self.backend {
+            write!(f, "{backend}:")?;
+        }
+        write!(f, "{}", self.selector)?;
+        if let Some(policy) = self.on_conflict {
+            write!(f, ",on-conflict={policy}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SourceSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (backend, rest) = match s.split_once(':') {
+            Some((b, rest)) if !b.contains('=') => (Some(b.parse()?), rest),
+            _ => (None, s),
+        };
+
+        let mut parts = rest.split(',');
+        let mut selector = match parts.next().unwrap_or_default().split_once('=') {
+            Some(("name", n)) if !n.is_empty() => Selector::Name(n.to_string()),
+            Some(("prefix", p)) => Selector::Prefix(p.to_string()),
+            Some(("path", p)) if !p.is_empty() => Selector::Path(PathSelector {
+                path: p.to_string(),
+                map: Vec::new(),
+            }),
+            _ => bail!("source must start with `name=SECRET`, `prefix=PREFIX` or `path=PATH`"),
+        };
+
+        let mut on_conflict = None;
+        for option in parts {
+            match option.split_once('=') {
+                Some(("map", m)) => {
+                    let Selector::Path(path) = &mut selector else {
+                        bail!("`map` can be used only with `path=PATH` sources");
+                    };
+                    let Some((field, var)) = m.rsplit_once(':') else {
+                        bail!("invalid mapping '{}', expected `FIELD:VAR`", m);
+                    };
+                    path.map.push(FieldMap {
+                        field: field.to_string(),
+                        var: var.to_string(),
+                    });
+                }
+                Some(("on-conflict", p)) => {
+                    on_conflict =
+                        Some(ConflictPolicy::from_str(p, false).map_err(|e| {
+                            anyhow::anyhow!("invalid conflict policy '{}': {}", p, e)
+                        })?)
+                }
+                _ => bail!("unknown source option '{}'", option),
+            }
+        }
+
+        Ok(Self {
+            backend,
+            selector,
+            on_conflict,
+        })
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.backend, self.selector)
+    }
+}
+
+impl Source {
+    /// The fully-specified `SourceSpec` of this source.
+    pub fn to_spec(&self) -> SourceSpec {
+        SourceSpec {
+            backend: Some(self.backend),
+            selector: self.selector.clone(),
+            on_conflict: Some(self.on_conflict),
+        }
+    }
+
+    pub fn download(&self) -> Result<Vec<(String, String)>> {
+        match &self.selector {
+            Selector::Name(n) => self.vault.download_json(n),
+            Selector::Prefix(p) => self.vault.download_prefixed(p),
+            Selector::Path(p) => self.vault.download_path(&p.path, &p.map),
+        }
+    }
+}
+
+/// Downloads all the sources, in order, using `download`, and merges them into a single list of
+/// variables.
+///
+/// Sources are applied one after another. When a source defines a variable that is already
+/// defined by one of the previous sources (with a different value), its `on_conflict` policy
+/// decides which value is used. Every such conflict is reported on stderr.
+pub fn download_sources<D>(sources: &[Source], mut download: D) -> Result<Vec<(String, String)>>
+where
+    D: FnMut(usize, &Source) -> Result<Vec<(String, String)>>,
+{
+    let layers = sources
+        .iter()
+        .enumerate()
+        .map(|(i, s)| {
+            let vars = download(i, s).map_err(|e| SourceError::Download(s.to_string(), e))?;
+            Ok(Layer {
+                label: s.to_string(),
+                vars,
+                on_conflict: s.on_conflict,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    merge_layers(layers, |msg| eprintln!("kvenv: {msg}"))
+}
+
+pub struct Layer {
+    pub label: String,
+    pub vars: Vec<(String, String)>,
+    pub on_conflict: ConflictPolicy,
+}
+
+pub fn merge_layers<R>(layers: Vec<Layer>, mut report: R) -> Result<Vec<(String, String)>>
+where
+    R: FnMut(String),
+{
+    let mut result: Vec<(String, String)> = Vec::new();
+    let mut owners: HashMap<String, (usize, usize)> = HashMap::new();
+
+    for (layer_idx, layer) in layers.iter().enumerate() {
+        for (key, value) in &layer.vars {
+            let Some(&(idx, owner)) = owners.get(key) else {
+                owners.insert(key.clone(), (result.len(), layer_idx));
+                result.push((key.clone(), value.clone()));
+                continue;
+            };
+
+            if owner == layer_idx || result[idx].1 == *value {
+                result[idx].1 = value.clone();
+                continue;
+            }
+
+            let first = &layers[owner].label;
+            match layer.on_conflict {
+                ConflictPolicy::Override => {
+                    report(format!(
+                        "'{key}' from {} overrides the value from {first}",
+                        layer.label
+                    ));
+                    result[idx].1 = value.clone();
+                    owners.insert(key.clone(), (idx, layer_idx));
+                }
+                ConflictPolicy::KeepFirst => {
+                    report(format!(
+                        "'{key}' from {} is ignored, keeping the value from {first}",
+                        layer.label
+                    ));
+                }
+                ConflictPolicy::Fail => {
+                    return Err(SourceError::Conflict {
+                        key: key.clone(),
+                        first: first.clone(),
+                        second: layer.label.clone(),
+                    }
+                    .into());
+                }
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! env {
+        ($a:expr, $b:expr) => {
+            ($a.to_string(), $b.to_string())
+        };
+    }
+
+    fn layer(label: &str, vars: Vec<(String, String)>, on_conflict: ConflictPolicy) -> Layer {
+        Layer {
+            label: label.to_string(),
+            vars,
+            on_conflict,
+        }
+    }
+
+    #[test]
+    fn parses_source_spec() {
+        assert_eq!(
+            SourceSpec {
+                backend: None,
+                selector: Selector::Name("shared/base".to_string()),
+                on_conflict: None,
+            },
+            "name=shared/base".parse().unwrap()
+        );
+        assert_eq!(
+            SourceSpec {
+                backend: Some(Backend::Aws),
+                selector: Selector::Prefix("svc-".to_string()),
+                on_conflict: Some(ConflictPolicy::KeepFirst),
+            },
+            "aws:prefix=svc-,on-conflict=keep-first".parse().unwrap()
+        );
+        assert_eq!(
+            SourceSpec {
+                backend: Some(Backend::Vault),
+                selector: Selector::Name("a:b".to_string()),
+                on_conflict: None,
+            },
+            "vault:name=a:b".parse().unwrap()
+        );
+        assert_eq!(
+            SourceSpec {
+                backend: Some(Backend::Vault),
+                selector: Selector::Path(PathSelector {
+                    path: "database/creds/app".to_string(),
+                    map: vec![FieldMap {
+                        field: "data.username".to_string(),
+                        var: "DB_USER".to_string(),
+                    }],
+                }),
+                on_conflict: Some(ConflictPolicy::Fail),
+            },
+            "vault:path=database/creds/app,map=data.username:DB_USER,on-conflict=fail"
+                .parse()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn source_spec_round_trips() {
+        struct Dummy;
+        impl Vault for Dummy {
+            fn download_prefixed(&self, _: &str) -> Result<Vec<(String, String)>> {
+                Ok(vec![])
+            }
+            fn download_json(&self, _: &str) -> Result<Vec<(String, String)>> {
+                Ok(vec![])
+            }
+        }
+
+        let source = Source {
+            backend: Backend::Google,
+            selector: Selector::Prefix("app-".to_string()),
+            on_conflict: ConflictPolicy::KeepFirst,
+            vault: Rc::new(Dummy),
+        };
+        assert_eq!(
+            SourceSpec {
+                backend: Some(Backend::Google),
+                selector: Selector::Prefix("app-".to_string()),
+                on_conflict: Some(ConflictPolicy::KeepFirst),
+            },
+            source.to_spec().to_string().parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_source_spec() {
+        assert!("".parse::<SourceSpec>().is_err());
+        assert!("name=".parse::<SourceSpec>().is_err());
+        assert!("secret=abc".parse::<SourceSpec>().is_err());
+        assert!("gcp:name=abc".parse::<SourceSpec>().is_err());
+        assert!("name=abc,on-conflict=merge".parse::<SourceSpec>().is_err());
+        assert!("name=abc,other=1".parse::<SourceSpec>().is_err());
+        assert!("name=abc,map=a:B".parse::<SourceSpec>().is_err());
+        assert!("path=abc,map=a".parse::<SourceSpec>().is_err());
+    }
+
+    #[test]
+    fn later_source_overrides_by_default() {
+        let mut reports = Vec::new();
+        let merged = merge_layers(
+            vec![
+                layer(
+                    "base",
+                    vec![env!("A", "1"), env!("B", "1")],
+                    ConflictPolicy::Override,
+                ),
+                layer(
+                    "svc",
+                    vec![env!("B", "2"), env!("C", "2")],
+                    ConflictPolicy::Override,
+                ),
+            ],
+            |m| reports.push(m),
+        )
+        .unwrap();
+
+        assert_eq!(vec![env!("A", "1"), env!("B", "2"), env!("C", "2")], merged);
+        assert_eq!(1, reports.len());
+        assert!(reports[0].contains("'B'"));
+    }
+
+    #[test]
+    fn keep_first_keeps_previous_value() {
+        let mut reports = Vec::new();
+        let merged = merge_layers(
+            vec![
+                layer("base", vec![env!("A", "1")], ConflictPolicy::Override),
+                layer("svc", vec![env!("A", "2")], ConflictPolicy::KeepFirst),
+                layer("last", vec![env!("A", "3")], ConflictPolicy::Override),
+            ],
+            |m| reports.push(m),
+        )
+        .unwrap();
+
+        assert_eq!(vec![env!("A", "3")], merged);
+        assert_eq!(2, reports.len());
+        assert!(reports[1].contains("from base"));
+    }
+
+    #[test]
+    fn fail_policy_reports_error() {
+        let err = merge_layers(
+            vec![
+                layer("base", vec![env!("A", "1")], ConflictPolicy::Override),
+                layer("svc", vec![env!("A", "2")], ConflictPolicy::Fail),
+            ],
+            |_| {},
+        )
+        .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<SourceError>(),
+            Some(SourceError::Conflict { key, .. }) if key == "A"
+        ));
+    }
+
+    #[test]
+    fn same_values_are_not_conflicts() {
+        let merged = merge_layers(
+            vec![
+                layer("base", vec![env!("A", "1")], ConflictPolicy::Override),
+                layer("svc", vec![env!("A", "1")], ConflictPolicy::Fail),
+            ],
+            |_| panic!("should not report"),
+        )
+        .unwrap();
+
+        assert_eq!(vec![env!("A", "1")], merged);
+    }
+}
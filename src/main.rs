@@ -1,15 +1,33 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
-use clap::{command, Parser, Subcommand};
+use clap::{error::ErrorKind, Parser, Subcommand, ValueHint};
 
 mod cache;
+mod ci;
+mod config;
+mod crypto;
 mod env;
+mod export;
+mod redact;
 mod run;
 mod run_in;
 mod run_with;
+mod watch;
 
 #[derive(Parser, Debug)]
 #[command(name = "kvenv", about, version, author, next_line_help = true)]
 struct Cli {
+    /// Use the named profile from the configuration file. Options specified on the command line
+    /// (or via environment variables) take precedence over the profile.
+    #[arg(long, global = true, env = "KVENV_PROFILE")]
+    profile: Option<String>,
+
+    /// Path to the configuration file. If not specified, `kvenv.toml` is searched for in the
+    /// current directory and its parents. User-level `~/.config/kvenv/kvenv.toml` is always used.
+    #[arg(long, global = true, env = "KVENV_CONFIG", value_parser, value_hint = ValueHint::FilePath)]
+    config: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Command,
 }
@@ -19,10 +37,26 @@ enum Command {
     Cache(cache::Cache),
     RunWith(run_with::RunWith),
     RunIn(run_in::RunIn),
+    Export(export::Export),
 }
 
 fn main() -> Result<()> {
-    let opts: Cli = Cli::parse();
+    let (args, profile) = config::apply_profile(std::env::args_os().collect())?;
+    let opts = match Cli::try_parse_from(args) {
+        Ok(opts) => opts,
+        Err(e) => {
+            if let Some(p) = profile.filter(|_| e.kind() == ErrorKind::MissingRequiredArgument) {
+                let files: Vec<_> = p.files.iter().map(|f| f.display().to_string()).collect();
+                eprintln!(
+                    "note: profile '{}' (from {}) is incomplete - specify the missing options \
+                    there or on the command line\n",
+                    p.name,
+                    files.join(", ")
+                );
+            }
+            e.exit()
+        }
+    };
     match opts.command {
         Command::Cache(c) => {
             cache::run_cache(c)?;
@@ -33,6 +67,9 @@ fn main() -> Result<()> {
         Command::RunIn(c) => {
             run_in::run_in(c)?;
         }
+        Command::Export(c) => {
+            export::run_export(c)?;
+        }
     }
     Ok(())
 }
@@ -48,6 +85,7 @@ mod tests {
         assert_correct(&["kvenv", "cache", "--help"]);
         assert_correct(&["kvenv", "run-in", "--help"]);
         assert_correct(&["kvenv", "run-with", "--help"]);
+        assert_correct(&["kvenv", "export", "--help"]);
     }
 
     fn assert_correct(args: &[&str]) {
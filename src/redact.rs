@@ -0,0 +1,216 @@
+use aho_corasick::{AhoCorasick, MatchKind};
+use base64::{engine::general_purpose, Engine};
+use std::io::{self, Read, Write};
+
+/// The text the secret values are replaced with.
+const REPLACEMENT: &[u8] = b"***";
+
+/// Values shorter than this are not redacted, as they would garble the output (think `1` or
+/// `true`) without hiding much.
+const MIN_REDACTED_LEN: usize = 4;
+
+/// Replaces the secret values (and their common encodings) in a stream of bytes.
+pub struct Redactor {
+    patterns: Vec<Vec<u8>>,
+    matcher: Option<AhoCorasick>,
+}
+
+/// URL-encodes the value, keeping only the unreserved characters from RFC 3986.
+fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// The value itself, along with its base64 and URL-encoded variants.
+fn variants(value: &str) -> Vec<String> {
+    let bytes = value.as_bytes();
+    vec![
+        value.to_string(),
+        general_purpose::STANDARD.encode(bytes),
+        general_purpose::STANDARD_NO_PAD.encode(bytes),
+        general_purpose::URL_SAFE.encode(bytes),
+        general_purpose::URL_SAFE_NO_PAD.encode(bytes),
+        url_encode(value),
+        url_encode(value).replace("%20", "+"),
+    ]
+}
+
+impl Redactor {
+    pub fn new<'a, I>(values: I) -> Self
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut patterns: Vec<Vec<u8>> = values
+            .into_iter()
+            .filter(|v| v.len() >= MIN_REDACTED_LEN)
+            .flat_map(variants)
+            .map(String::into_bytes)
+            .collect();
+        patterns.sort();
+        patterns.dedup();
+
+        let matcher = if patterns.is_empty() {
+            None
+        } else {
+            let matcher = AhoCorasick::builder()
+                .match_kind(MatchKind::LeftmostLongest)
+                .build(&patterns)
+                .expect("secret values should be valid patterns");
+            Some(matcher)
+        };
+        Self { patterns, matcher }
+    }
+
+    /// Whether the data can be the beginning of a secret that continues in the next chunk.
+    fn is_incomplete(&self, tail: &[u8]) -> bool {
+        self.patterns
+            .iter()
+            .any(|p| p.len() > tail.len() && p.starts_with(tail))
+    }
+
+    /// Redacts `buf` and returns how many bytes from its beginning were processed. The rest needs
+    /// to be passed again along with more data, unless it is the end of the stream.
+    fn redact(&self, buf: &[u8], is_last: bool, out: &mut Vec<u8>) -> usize {
+        let Some(matcher) = &self.matcher else {
+            out.extend_from_slice(buf);
+            return buf.len();
+        };
+
+        let max_len = self.patterns.iter().map(Vec::len).max().unwrap_or(0);
+        let hold = if is_last {
+            buf.len()
+        } else {
+            (buf.len().saturating_sub(max_len - 1)..buf.len())
+                .find(|p| self.is_incomplete(&buf[*p..]))
+                .unwrap_or(buf.len())
+        };
+
+        let mut pos = 0;
+        for m in matcher.find_iter(buf) {
+            if m.start() >= hold {
+                break;
+            }
+            out.extend_from_slice(&buf[pos..m.start()]);
+            out.extend_from_slice(REPLACEMENT);
+            pos = m.end();
+        }
+        let end = hold.max(pos);
+        out.extend_from_slice(&buf[pos..end]);
+        end
+    }
+
+    /// Copies everything from `r` to `w`, redacting the secrets. The output is flushed after every
+    /// read, so interactive output is not delayed (except for possible beginnings of secrets).
+    pub fn pump<R: Read, W: Write>(&self, mut r: R, mut w: W) -> io::Result<()> {
+        let mut buf = vec![0; 8192];
+        let mut pending = Vec::new();
+        let mut out = Vec::new();
+        loop {
+            let read = match r.read(&mut buf) {
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            pending.extend_from_slice(&buf[..read]);
+
+            let is_last = read == 0;
+            let processed = self.redact(&pending, is_last, &mut out);
+            pending.drain(..processed);
+            w.write_all(&out)?;
+            w.flush()?;
+            out.clear();
+
+            if is_last {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Splits the input into chunks of the given size, to simulate split reads.
+    struct Chunked<'a>(&'a [u8], usize);
+
+    impl Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.len().min(self.1).min(buf.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    fn redact(redactor: &Redactor, input: &str, chunk: usize) -> String {
+        let mut out = Vec::new();
+        redactor
+            .pump(Chunked(input.as_bytes(), chunk), &mut out)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn redacts_values_and_encodings() {
+        let redactor = Redactor::new(["p@ss word", "token"]);
+        let input = "a p@ss word b cEBzcyB3b3Jk c p%40ss%20word d p%40ss+word e dG9rZW4= f";
+
+        assert_eq!(
+            "a *** b *** c *** d *** e *** f",
+            redact(&redactor, input, 8192)
+        );
+    }
+
+    #[test]
+    fn redacts_values_split_across_reads() {
+        let redactor = Redactor::new(["secret", "secret-longer"]);
+        let input = "xsecretx secret-longer secret-long secre";
+
+        for chunk in 1..input.len() {
+            assert_eq!(
+                "x***x *** ***-long secre",
+                redact(&redactor, input, chunk),
+                "chunk size {chunk}"
+            );
+        }
+    }
+
+    #[test]
+    fn prefers_earlier_value_over_overlapping_one() {
+        let redactor = Redactor::new(["abcdef", "cdxy"]);
+
+        for chunk in 1..8 {
+            assert_eq!("***", redact(&redactor, "abcdef", chunk));
+            assert_eq!("ab***", redact(&redactor, "abcdxy", chunk));
+        }
+    }
+
+    #[test]
+    fn ignores_short_values() {
+        assert_eq!(4, MIN_REDACTED_LEN);
+        let redactor = Redactor::new(["1", "abc", "abcd"]);
+        assert_eq!("1 abc ***", redact(&redactor, "1 abc abcd", 2));
+    }
+
+    #[test]
+    fn does_not_hold_unrelated_output() {
+        let redactor = Redactor::new(["secret"]);
+        let mut out = Vec::new();
+        let processed = redactor.redact(b"prompt> ", false, &mut out);
+        assert_eq!(8, processed);
+        assert_eq!(b"prompt> ".to_vec(), out);
+
+        out.clear();
+        let processed = redactor.redact(b"a sec", false, &mut out);
+        assert_eq!(2, processed);
+        assert_eq!(b"a ".to_vec(), out);
+    }
+}
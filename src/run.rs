@@ -1,30 +1,291 @@
 use anyhow::Result;
-use std::process::{Command, ExitStatus, Output, Stdio};
+use clap::Args;
+use std::{
+    convert::Infallible,
+    io,
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    sync::Arc,
+    thread,
+};
 
 use crate::env::ProcessEnv;
+use crate::redact::Redactor;
 
-fn run_with_output<F>(env: ProcessEnv, command: Vec<String>, stdio: F) -> Result<Output>
+/// How the command is started.
+#[derive(Args, Debug, Default)]
+pub struct RunMode {
+    /// Replace the kvenv process with the command (using `execve`), so the command receives
+    /// signals directly and kvenv disappears from the process tree. This is the default on Unix.
+    #[arg(long, overrides_with = "no_exec")]
+    exec: bool,
+
+    /// Run the command as a child process and wait for it to finish.
+    #[arg(long, overrides_with = "exec")]
+    no_exec: bool,
+
+    /// Pipe the command's stdout and stderr through kvenv, replacing the downloaded secret values
+    /// (and their base64 and URL-encoded variants) with `***`. Values shorter than 4 characters
+    /// are left as they are. The command is always run as a child process then, and its output is
+    /// not a terminal.
+    #[arg(long)]
+    redact_output: bool,
+}
+
+impl RunMode {
+    pub fn is_exec(&self) -> bool {
+        if self.redact_output {
+            false
+        } else if cfg!(unix) {
+            !self.no_exec
+        } else {
+            self.exec
+        }
+    }
+
+    pub fn redact_output(&self) -> bool {
+        self.redact_output
+    }
+}
+
+/// Forwards the signals kvenv receives to the child process while it is running.
+#[cfg(unix)]
+pub mod forward {
+    use signal_hook::{consts::signal::*, iterator::Signals};
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicBool, AtomicU32, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    const FORWARDED_SIGNALS: &[i32] = &[SIGINT, SIGTERM, SIGHUP, SIGQUIT, SIGUSR1, SIGUSR2];
+    const TERMINATING_SIGNALS: &[i32] = &[SIGINT, SIGTERM, SIGQUIT];
+
+    pub struct SignalForwarder {
+        signals: Signals,
+    }
+
+    pub struct RunningForwarder {
+        handle: signal_hook::iterator::Handle,
+        thread: thread::JoinHandle<()>,
+        pid: Arc<AtomicU32>,
+        terminating: Arc<AtomicBool>,
+    }
+
+    impl SignalForwarder {
+        /// Starts catching the signals. This needs to happen before the child is spawned, so no
+        /// signal is lost (or kills kvenv) in between.
+        pub fn new() -> io::Result<Self> {
+            Ok(Self {
+                signals: Signals::new(FORWARDED_SIGNALS)?,
+            })
+        }
+
+        pub fn forward_to(mut self, pid: u32) -> RunningForwarder {
+            let handle = self.signals.handle();
+            let pid = Arc::new(AtomicU32::new(pid));
+            let terminating = Arc::new(AtomicBool::new(false));
+            let thread = thread::spawn({
+                let pid = pid.clone();
+                let terminating = terminating.clone();
+                move || {
+                    for signal in self.signals.forever() {
+                        if TERMINATING_SIGNALS.contains(&signal) {
+                            terminating.store(true, Ordering::SeqCst);
+                        }
+                        let pid = pid.load(Ordering::SeqCst) as libc::pid_t;
+                        // SAFETY: `kill` has no memory safety requirements.
+                        unsafe { libc::kill(pid, signal) };
+                    }
+                }
+            });
+            RunningForwarder {
+                handle,
+                thread,
+                pid,
+                terminating,
+            }
+        }
+    }
+
+    impl RunningForwarder {
+        /// Forwards the signals to another process from now on (e.g. a restarted child).
+        pub fn retarget(&self, pid: u32) {
+            self.pid.store(pid, Ordering::SeqCst);
+        }
+
+        /// Whether kvenv was asked to terminate (by `SIGINT`, `SIGTERM` or `SIGQUIT`).
+        pub fn is_terminating(&self) -> bool {
+            self.terminating.load(Ordering::SeqCst)
+        }
+
+        pub fn stop(self) {
+            self.handle.close();
+            let _ = self.thread.join();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub mod forward {
+    pub struct SignalForwarder;
+    pub struct RunningForwarder;
+
+    impl SignalForwarder {
+        pub fn new() -> std::io::Result<Self> {
+            Ok(Self)
+        }
+
+        pub fn forward_to(self, _pid: u32) -> RunningForwarder {
+            RunningForwarder
+        }
+    }
+
+    impl RunningForwarder {
+        pub fn retarget(&self, _pid: u32) {}
+
+        pub fn is_terminating(&self) -> bool {
+            false
+        }
+
+        pub fn stop(self) {}
+    }
+}
+
+fn build_command(env: ProcessEnv, command: &[String]) -> Command {
+    let env = env.into_env();
+
+    let mut cmd = Command::new(&command[0]);
+    cmd.args(command.iter().skip(1)).env_clear().envs(&env);
+    cmd
+}
+
+fn spawn<F>(env: ProcessEnv, command: &[String], stdio: F) -> Result<Child>
 where
     F: Fn() -> Stdio,
 {
-    let env = env.into_env();
-
-    let child = Command::new(&command[0])
-        .args(command.iter().skip(1))
-        .env_clear()
-        .envs(&env)
+    let child = build_command(env, command)
         .stdout(stdio())
         .stdin(stdio())
         .stderr(stdio())
         .spawn()?;
 
-    let output = child.wait_with_output()?;
+    Ok(child)
+}
 
-    Ok(output)
+/// A running command, along with the threads redacting its output (if enabled).
+pub struct Running {
+    pub child: Child,
+    pumps: Vec<thread::JoinHandle<io::Result<()>>>,
 }
 
-pub fn run_in_env(env: ProcessEnv, command: Vec<String>) -> Result<ExitStatus> {
-    Ok(run_with_output(env, command, Stdio::inherit)?.status)
+impl Running {
+    /// Waits until all the output of the command is written. Should be called after the command
+    /// exits.
+    pub fn finish(self) -> io::Result<()> {
+        for pump in self.pumps {
+            pump.join().expect("output redaction should not panic")?;
+        }
+        Ok(())
+    }
+}
+
+/// Starts the command with inherited stdio, or with its output redacted.
+pub fn start(env: ProcessEnv, command: &[String], redact: bool) -> Result<Running> {
+    if !redact {
+        let child = spawn(env, command, Stdio::inherit)?;
+        return Ok(Running {
+            child,
+            pumps: Vec::new(),
+        });
+    }
+
+    let redactor = Arc::new(Redactor::new(env.secret_values()));
+    let mut child = build_command(env, command)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stdout = child.stdout.take().expect("stdout should be piped");
+    let stderr = child.stderr.take().expect("stderr should be piped");
+    let pumps = vec![
+        thread::spawn({
+            let redactor = redactor.clone();
+            move || redactor.pump(stdout, io::stdout())
+        }),
+        thread::spawn(move || redactor.pump(stderr, io::stderr())),
+    ];
+    Ok(Running { child, pumps })
+}
+
+fn run_with_output<F>(env: ProcessEnv, command: Vec<String>, stdio: F) -> Result<Output>
+where
+    F: Fn() -> Stdio,
+{
+    let forwarder = forward::SignalForwarder::new()?;
+    let child = spawn(env, &command, stdio)?;
+
+    let forwarder = forwarder.forward_to(child.id());
+    let output = child.wait_with_output();
+    forwarder.stop();
+
+    Ok(output?)
+}
+
+pub fn run_in_env(env: ProcessEnv, command: Vec<String>, redact: bool) -> Result<ExitStatus> {
+    if !redact {
+        return Ok(run_with_output(env, command, Stdio::inherit)?.status);
+    }
+
+    let forwarder = forward::SignalForwarder::new()?;
+    let mut running = start(env, &command, true)?;
+
+    let forwarder = forwarder.forward_to(running.child.id());
+    let status = running.child.wait();
+    forwarder.stop();
+
+    let status = status?;
+    running.finish()?;
+    Ok(status)
+}
+
+/// Replaces the current process with the command. Returns only if the command cannot be executed.
+#[cfg(unix)]
+pub fn exec_in_env(env: ProcessEnv, command: Vec<String>) -> Result<Infallible> {
+    use std::os::unix::process::CommandExt;
+
+    let error = build_command(env, &command).exec();
+    Err(error.into())
+}
+
+#[cfg(not(unix))]
+pub fn exec_in_env(_env: ProcessEnv, _command: Vec<String>) -> Result<Infallible> {
+    anyhow::bail!("--exec is supported on Unix only")
+}
+
+/// The exit code kvenv should use for the command's status. A command killed by a signal results
+/// in 128 + signal number, like in POSIX shells.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    -1
+}
+
+/// Exits kvenv with the exit code of the command.
+pub fn exit_with(status: ExitStatus) -> ! {
+    std::process::exit(exit_code(status))
 }
 
 #[cfg(test)]
@@ -74,4 +335,47 @@ mod tests {
         assert!(!failed_exec.status.success());
         assert_eq!(10, failed_exec.status.code().unwrap());
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn exec_fails_expectedly() {
+        let env = ProcessEnv::fresh(vec![], vec![], vec![]);
+        assert!(exec_in_env(env, vec!["this-does-not-exist".to_string()]).is_err());
+    }
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["/bin/sh".to_string(), "-c".to_string(), script.to_string()]
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_signals_as_exit_codes() {
+        let env = ProcessEnv::fresh(vec![], vec![], vec![]);
+        let output = run_with_output(env, sh("kill -TERM $$"), Stdio::piped).unwrap();
+        assert_eq!(128 + 15, exit_code(output.status));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn forwards_signals_to_child() {
+        let env = ProcessEnv::fresh(vec![], vec![], vec![]);
+        let script = "trap 'exit 42' USR1; kill -USR1 $PPID; sleep 5 & wait";
+        let output = run_with_output(env, sh(script), Stdio::piped).unwrap();
+        assert_eq!(Some(42), output.status.code());
+    }
+
+    #[test]
+    fn exec_is_default_on_unix() {
+        assert_eq!(cfg!(unix), RunMode::default().is_exec());
+        let no_exec = RunMode {
+            no_exec: true,
+            ..Default::default()
+        };
+        assert!(!no_exec.is_exec());
+        let redacted = RunMode {
+            redact_output: true,
+            ..Default::default()
+        };
+        assert!(!redacted.is_exec());
+    }
 }
@@ -1,9 +1,11 @@
 use anyhow::Result;
-use clap::{arg, Args};
+use clap::Args;
 use thiserror::Error;
 
-use crate::env::{download_env, EnvConfig};
-use crate::run;
+use crate::ci::{self, CiConfig};
+use crate::env::{EnvConfig, EnvDownloader, LeaseKeeper};
+use crate::run::{self, RunMode};
+use crate::watch::{self, WatchConfig};
 
 #[derive(Error, Debug)]
 pub enum RunInError {
@@ -11,6 +13,8 @@ pub enum RunInError {
     LoadError(#[source] anyhow::Error),
     #[error("cannot run the specified command")]
     RunError(#[source] anyhow::Error),
+    #[error("cannot set up the CI integration")]
+    Ci(#[source] ci::CiError),
 }
 
 /// Runs the command with the specified argument using freshly downloaded environment.
@@ -19,21 +23,62 @@ pub struct RunIn {
     #[command(flatten)]
     env: EnvConfig,
 
+    #[command(flatten)]
+    mode: RunMode,
+
+    #[command(flatten)]
+    watch: WatchConfig,
+
+    #[command(flatten)]
+    ci: CiConfig,
+
     /// The command to execute
     #[arg(name = "COMMAND", required = true)]
     command: Vec<String>,
 }
 
+fn run_watching(cfg: RunIn) -> Result<std::convert::Infallible> {
+    let downloader = EnvDownloader::new(cfg.env, false).map_err(RunInError::LoadError)?;
+    let env = downloader.download().map_err(RunInError::LoadError)?;
+    let leases = LeaseKeeper::start(downloader.take_leases());
+    if let Err(e) = cfg.ci.apply(&env) {
+        leases.stop();
+        return Err(RunInError::Ci(e).into());
+    }
+
+    let redact = cfg.mode.redact_output() || cfg.ci.redact_output();
+    // The leased credentials are downloaded only once, so the leases stay the same while polling.
+    let status = watch::run_watching(&cfg.watch, env, &cfg.command, redact, || {
+        let env = downloader.download()?;
+        cfg.ci.mask(&env)?;
+        Ok(env)
+    });
+    leases.stop();
+    let status = status.map_err(|x| anyhow::Error::new(RunInError::RunError(x)))?;
+    run::exit_with(status)
+}
+
 pub fn run_in(cfg: RunIn) -> Result<std::convert::Infallible> {
-    let env = download_env(cfg.env, false).map_err(RunInError::LoadError)?;
-
-    let status = run::run_in_env(env, cfg.command)
-        .map_err(|x| anyhow::Error::new(RunInError::RunError(x)))?;
-    if status.success() {
-        std::process::exit(0)
-    } else if let Some(code) = status.code() {
-        std::process::exit(code)
-    } else {
-        std::process::exit(-1)
+    if cfg.watch.is_enabled() {
+        return run_watching(cfg);
+    }
+    let downloader = EnvDownloader::new(cfg.env, false).map_err(RunInError::LoadError)?;
+    let env = downloader.download().map_err(RunInError::LoadError)?;
+    let leases = LeaseKeeper::start(downloader.take_leases());
+    if let Err(e) = cfg.ci.apply(&env) {
+        leases.stop();
+        return Err(RunInError::Ci(e).into());
     }
+
+    // The leases need kvenv to outlive the command, so it can revoke them once the command exits.
+    let redact = cfg.mode.redact_output() || cfg.ci.redact_output();
+    if cfg.mode.is_exec() && !redact && leases.is_empty() {
+        let err = run::exec_in_env(env, cfg.command).unwrap_err();
+        return Err(RunInError::RunError(err).into());
+    }
+
+    let status = run::run_in_env(env, cfg.command, redact);
+    leases.stop();
+    let status = status.map_err(|x| anyhow::Error::new(RunInError::RunError(x)))?;
+    run::exit_with(status)
 }
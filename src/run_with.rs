@@ -1,24 +1,35 @@
 use anyhow::Result;
-use clap::{arg, Args, ValueHint};
+use clap::{Args, ValueHint};
 use std::{
     fs,
     path::{Path, PathBuf},
+    process::ExitStatus,
 };
 use thiserror::Error;
 
-use crate::env::ProcessEnv;
-use crate::run;
+use crate::ci::{CiConfig, CiError};
+use crate::crypto::{CryptoError, DecryptionConfig};
+use crate::env::{download_env, EnvConfig, EnvFileError, ProcessEnv};
+use crate::run::{self, RunMode};
 
 #[derive(Error, Debug)]
 pub enum RunWithError {
     #[error("cannot load environment file")]
-    Load(#[from] serde_json::error::Error),
+    Load(#[from] EnvFileError),
     #[error("cannot load environment file - io error")]
     Io(#[source] std::io::Error),
+    #[error("cannot decrypt environment file")]
+    Decrypt(#[source] CryptoError),
+    #[error("the environment file expired at {0}")]
+    Expired(String),
+    #[error("cannot download the expired environment again")]
+    Refresh(#[source] anyhow::Error),
     #[error("cannot remove the env file")]
     Cleanup(#[source] std::io::Error),
     #[error("cannot run the specified command")]
     Run(#[source] anyhow::Error),
+    #[error("cannot set up the CI integration")]
+    Ci(#[source] CiError),
 }
 
 /// Runs the command with the specified argument using cached environment.
@@ -29,35 +40,132 @@ pub struct RunWith {
     #[arg(short, long, value_parser, value_hint = ValueHint::FilePath)]
     env_file: PathBuf,
 
-    /// If set, the env file will be removed after execution.
+    /// If set, the env file will be removed after execution, even if the command fails or is
+    /// interrupted by a signal. The command is always run as a child process then, as kvenv needs
+    /// to outlive it.
     #[arg(short, long)]
     cleanup: bool,
 
+    /// If set and the env file is expired, the environment is downloaded again from the sources
+    /// recorded in the file instead of failing. Credentials are taken from the environment, as the
+    /// file does not store them. The env file itself is not updated.
+    #[arg(long)]
+    refresh_expired: bool,
+
+    /// Print the versions of the secrets the environment was downloaded from (as recorded in the
+    /// env file) to stderr before running the command.
+    #[arg(long)]
+    show_versions: bool,
+
+    #[command(flatten)]
+    decryption: DecryptionConfig,
+
+    #[command(flatten)]
+    mode: RunMode,
+
+    #[command(flatten)]
+    ci: CiConfig,
+
     /// The command to execute
     #[arg(name = "COMMAND", required = true, last = true)]
     command: Vec<String>,
 }
 
-fn load_env(path: &Path) -> Result<ProcessEnv> {
+fn load_env(path: &Path, decryption: &DecryptionConfig) -> Result<ProcessEnv> {
     let file = fs::File::open(path).map_err(RunWithError::Io)?;
-    let env = ProcessEnv::from_reader(&file).map_err(RunWithError::Load)?;
+    let contents = decryption.read(file).map_err(|e| match e {
+        CryptoError::Io(e) => RunWithError::Io(e),
+        e => RunWithError::Decrypt(e),
+    })?;
+    let env = ProcessEnv::from_reader(&contents[..]).map_err(RunWithError::Load)?;
     Ok(env)
 }
 
-pub fn run_with(cfg: RunWith) -> Result<std::convert::Infallible> {
-    let env = load_env(&cfg.env_file)?;
+fn refresh_env(env: ProcessEnv) -> Result<ProcessEnv> {
+    let Some(origin) = env.origin_args() else {
+        return Err(RunWithError::Refresh(anyhow::anyhow!(
+            "the environment file does not record its sources"
+        ))
+        .into());
+    };
+    let cfg = EnvConfig::from_origin(&origin).map_err(RunWithError::Refresh)?;
+    let fresh = download_env(cfg, false).map_err(RunWithError::Refresh)?;
+    Ok(env.refresh(fresh))
+}
 
-    let status =
-        run::run_in_env(env, cfg.command).map_err(|x| anyhow::Error::new(RunWithError::Run(x)))?;
-    if status.success() {
-        if cfg.cleanup {
-            fs::remove_file(&cfg.env_file).map_err(RunWithError::Cleanup)?;
+fn show_versions(env: &ProcessEnv) {
+    if env.versions().is_empty() {
+        eprintln!("kvenv: the environment file does not record the versions of the secrets");
+    }
+    for version in env.versions() {
+        eprintln!("kvenv: running with {version}");
+    }
+}
+
+fn run(cfg: &RunWith, mut env: ProcessEnv) -> Result<ExitStatus> {
+    if let Some(expired_at) = env.expired_at() {
+        if !cfg.refresh_expired {
+            let expired_at = humantime::format_rfc3339_seconds(expired_at).to_string();
+            return Err(RunWithError::Expired(expired_at).into());
         }
+        env = refresh_env(env)?;
+    }
+    if cfg.show_versions {
+        show_versions(&env);
+    }
+    cfg.ci.apply(&env).map_err(RunWithError::Ci)?;
+
+    let redact = cfg.mode.redact_output() || cfg.ci.redact_output();
+    if cfg.mode.is_exec() && !cfg.cleanup && !redact {
+        let err = run::exec_in_env(env, cfg.command.clone()).unwrap_err();
+        return Err(RunWithError::Run(err).into());
+    }
+
+    let status = run::run_in_env(env, cfg.command.clone(), redact)
+        .map_err(|x| anyhow::Error::new(RunWithError::Run(x)))?;
+    Ok(status)
+}
+
+pub fn run_with(cfg: RunWith) -> Result<std::convert::Infallible> {
+    let result = load_env(&cfg.env_file, &cfg.decryption).and_then(|env| run(&cfg, env));
+    if cfg.cleanup {
+        // The signals are forwarded to the command, so kvenv gets here even if it is interrupted.
+        let removed = fs::remove_file(&cfg.env_file).map_err(RunWithError::Cleanup);
+        if let (Ok(_), Err(e)) = (&result, removed) {
+            return Err(e.into());
+        }
+    }
+    run::exit_with(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Opts {
+        #[command(flatten)]
+        run_with: RunWith,
+    }
+
+    #[test]
+    fn cleans_up_invalid_env_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("env.json");
+        fs::write(&path, "not json").unwrap();
 
-        std::process::exit(0)
-    } else if let Some(code) = status.code() {
-        std::process::exit(code)
-    } else {
-        std::process::exit(-1)
+        let opts = Opts::try_parse_from([
+            "kvenv".as_ref(),
+            "--env-file".as_ref(),
+            path.as_os_str(),
+            "--cleanup".as_ref(),
+            "--".as_ref(),
+            "true".as_ref(),
+        ])
+        .unwrap();
+        assert!(run_with(opts.run_with).is_err());
+        assert!(!path.exists());
     }
 }
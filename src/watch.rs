@@ -0,0 +1,259 @@
+use anyhow::Result;
+use clap::{Args, ValueEnum};
+use std::{
+    io,
+    process::{Child, ExitStatus},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::env::ProcessEnv;
+use crate::run::{self, forward, Running};
+
+/// How often kvenv checks whether the command is still running.
+const CHILD_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Restarting of the command when the secrets change.
+#[derive(Args, Debug)]
+pub struct WatchConfig {
+    /// Poll the secret storage periodically and restart the command when the environment changes.
+    /// The command is always run as a child process then.
+    #[arg(long)]
+    watch: bool,
+
+    /// How often the secret storage is polled in the watch mode (e.g. `30s` or `5m`).
+    #[arg(
+        long,
+        value_parser = humantime::parse_duration,
+        default_value = "5m",
+        requires = "watch"
+    )]
+    interval: Duration,
+
+    /// The signal sent to the command to stop it before it is restarted.
+    #[arg(long, value_enum, default_value_t, requires = "watch")]
+    restart_signal: RestartSignal,
+
+    /// How long to wait for the command to stop after the restart signal is sent. The command is
+    /// killed afterwards.
+    #[arg(
+        long,
+        value_parser = humantime::parse_duration,
+        default_value = "10s",
+        requires = "watch"
+    )]
+    grace_period: Duration,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum RestartSignal {
+    #[default]
+    Term,
+    Int,
+    Hup,
+    Quit,
+    Usr1,
+    Usr2,
+}
+
+impl RestartSignal {
+    #[cfg(unix)]
+    fn to_raw(self) -> libc::c_int {
+        match self {
+            RestartSignal::Term => libc::SIGTERM,
+            RestartSignal::Int => libc::SIGINT,
+            RestartSignal::Hup => libc::SIGHUP,
+            RestartSignal::Quit => libc::SIGQUIT,
+            RestartSignal::Usr1 => libc::SIGUSR1,
+            RestartSignal::Usr2 => libc::SIGUSR2,
+        }
+    }
+}
+
+impl WatchConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.watch
+    }
+}
+
+#[cfg(unix)]
+fn send_signal(child: &mut Child, signal: RestartSignal) -> io::Result<()> {
+    // SAFETY: `kill` has no memory safety requirements.
+    if unsafe { libc::kill(child.id() as libc::pid_t, signal.to_raw()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_signal(child: &mut Child, _signal: RestartSignal) -> io::Result<()> {
+    child.kill()
+}
+
+/// Stops the command gracefully, killing it if it does not stop within the grace period.
+fn stop(child: &mut Child, cfg: &WatchConfig) -> io::Result<()> {
+    if child.try_wait()?.is_some() {
+        return Ok(());
+    }
+    send_signal(child, cfg.restart_signal)?;
+
+    let deadline = Instant::now() + cfg.grace_period;
+    while Instant::now() < deadline {
+        if child.try_wait()?.is_some() {
+            return Ok(());
+        }
+        thread::sleep(CHILD_POLL_INTERVAL);
+    }
+
+    eprintln!(
+        "kvenv: the command did not stop within {}, killing it",
+        humantime::format_duration(cfg.grace_period)
+    );
+    child.kill()?;
+    child.wait()?;
+    Ok(())
+}
+
+fn supervise<F>(
+    cfg: &WatchConfig,
+    mut env: ProcessEnv,
+    command: &[String],
+    redact: bool,
+    mut poll: F,
+    running: &mut Option<Running>,
+    forwarder: &forward::RunningForwarder,
+) -> Result<ExitStatus>
+where
+    F: FnMut() -> Result<ProcessEnv>,
+{
+    let mut next_poll = Instant::now() + cfg.interval;
+    loop {
+        let child = &mut running
+            .as_mut()
+            .expect("the command should be running")
+            .child;
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if Instant::now() < next_poll {
+            thread::sleep(CHILD_POLL_INTERVAL);
+            continue;
+        }
+
+        next_poll = Instant::now() + cfg.interval;
+        let fresh = match poll() {
+            Ok(fresh) => fresh,
+            Err(e) => {
+                eprintln!("kvenv: cannot check the secrets for changes: {e:#}");
+                continue;
+            }
+        };
+        let changed = env.changed_keys(&fresh);
+        if changed.is_empty() || forwarder.is_terminating() {
+            continue;
+        }
+
+        eprintln!(
+            "kvenv: secrets changed ({}), restarting the command",
+            changed.join(", ")
+        );
+        stop(child, cfg)?;
+        if forwarder.is_terminating() {
+            // kvenv was asked to terminate while the command was stopping.
+            return Ok(child.wait()?);
+        }
+        if let Some(stopped) = running.take() {
+            stopped.finish()?;
+        }
+        env = fresh;
+        let restarted = running.insert(run::start(env.clone(), command, redact)?);
+        forwarder.retarget(restarted.child.id());
+        next_poll = Instant::now() + cfg.interval;
+    }
+}
+
+/// Runs the command and restarts it every time `poll` returns environment with different
+/// downloaded variables. Returns the exit status of the command once it exits on its own.
+pub fn run_watching<F>(
+    cfg: &WatchConfig,
+    env: ProcessEnv,
+    command: &[String],
+    redact: bool,
+    poll: F,
+) -> Result<ExitStatus>
+where
+    F: FnMut() -> Result<ProcessEnv>,
+{
+    let forwarder = forward::SignalForwarder::new()?;
+    let running = run::start(env.clone(), command, redact)?;
+    let forwarder = forwarder.forward_to(running.child.id());
+
+    let mut running = Some(running);
+    let result = supervise(cfg, env, command, redact, poll, &mut running, &forwarder);
+    forwarder.stop();
+    if let Some(running) = running {
+        running.finish()?;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(value: &str) -> ProcessEnv {
+        ProcessEnv::fresh(vec![], vec![("V".to_string(), value.to_string())], vec![])
+    }
+
+    fn cfg(grace_period: Duration) -> WatchConfig {
+        WatchConfig {
+            watch: true,
+            interval: Duration::from_millis(50),
+            restart_signal: RestartSignal::Term,
+            grace_period,
+        }
+    }
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["/bin/sh".to_string(), "-c".to_string(), script.to_string()]
+    }
+
+    #[test]
+    fn restarts_command_when_env_changes() {
+        // Polls are popped from the end: unchanged, failed, then changed environment.
+        let mut polls = vec![
+            Ok(env("2")),
+            Err(anyhow::anyhow!("unavailable")),
+            Ok(env("1")),
+        ];
+        let command = sh(r#"test "$V" = 2 && exit 7; sleep 5 & wait"#);
+
+        let status = run_watching(
+            &cfg(Duration::from_secs(5)),
+            env("1"),
+            &command,
+            false,
+            || polls.pop().unwrap_or_else(|| Ok(env("2"))),
+        )
+        .unwrap();
+        assert_eq!(Some(7), status.code());
+    }
+
+    #[test]
+    fn kills_command_after_grace_period() {
+        let mut polls = vec![];
+        let command = sh(r#"trap '' TERM; test "$V" = 2 && exit 7; while :; do sleep 0.1; done"#);
+
+        let started = Instant::now();
+        let status = run_watching(
+            &cfg(Duration::from_millis(300)),
+            env("1"),
+            &command,
+            false,
+            || polls.pop().unwrap_or_else(|| Ok(env("2"))),
+        )
+        .unwrap();
+        assert_eq!(Some(7), status.code());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}